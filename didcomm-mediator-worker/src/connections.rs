@@ -1,12 +1,18 @@
 use crate::KV;
 use async_trait::async_trait;
-use didcomm_mediator::connections::{Connection, ConnectionStorage};
+use didcomm_mediator::connections::{is_expired, Connection, ConnectionStorage, DeviceInfo};
 use didcomm_rs::Message;
 use serde::Deserialize;
-use serde_json::Value;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
 use wasm_bindgen::prelude::*;
 use worker::*;
 
+/// Queued messages are stored one per key, `messages/{did}/{millis}-{id}`,
+/// so queueing is a single put that never overwrites a concurrent one. The
+/// connection key `{did}` only holds the endpoint and the device.
+const MESSAGES: &str = "messages/";
+
 pub fn get(key: String) -> Value {
     let value = futures::executor::block_on(async { KV::get(key).await });
     JsValue::into_serde(&value).unwrap()
@@ -17,6 +23,81 @@ pub fn put(did: String, value: Value) {
     futures::executor::block_on(async { KV::put(did.to_string(), value).await });
 }
 
+pub fn delete(did: String) {
+    futures::executor::block_on(async { KV::delete(did).await });
+}
+
+/// Keys starting with `prefix`, following the cursor over every page.
+pub fn list(prefix: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let options = JsValue::from_serde(&json!({"prefix": prefix, "cursor": cursor})).unwrap();
+        let value = futures::executor::block_on(async { KV::list(options).await });
+        let value: Value = JsValue::into_serde(&value).unwrap();
+        names.extend(
+            value["keys"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|key| key["name"].as_str().map(|name| name.to_string())),
+        );
+        cursor = value["cursor"].as_str().map(|cursor| cursor.to_string());
+        if value["list_complete"].as_bool().unwrap_or(true) || cursor.is_none() {
+            return names;
+        }
+    }
+}
+
+fn messages_prefix(did: &str) -> String {
+    format!("{}{}/", MESSAGES, did)
+}
+
+fn message_key(did: &str, millis: u64, message: &Message) -> String {
+    format!(
+        "{}{:016}-{}",
+        messages_prefix(did),
+        millis,
+        message.get_didcomm_header().id
+    )
+}
+
+/// Keys of the queued messages of `did`, oldest first.
+fn message_keys(did: &str) -> Vec<String> {
+    let mut keys = list(&messages_prefix(did));
+    keys.sort();
+    keys
+}
+
+fn load_message(key: &str) -> Option<Message> {
+    serde_json::from_value(get(key.to_string())).unwrap_or(None)
+}
+
+fn load(did: &str) -> Option<Connection> {
+    serde_json::from_value(get(did.to_string())).unwrap_or(None)
+}
+
+fn save(connection: &Connection) {
+    put(
+        connection.did.to_string(),
+        serde_json::to_value(connection).unwrap(),
+    );
+}
+
+/// Messages still queued inside the connection record by earlier versions.
+fn take_legacy(did: &str, batch_size: usize) -> Vec<Message> {
+    match load(did) {
+        Some(mut connection) if !connection.messages.is_empty() => {
+            connection.remove_expired();
+            let count = batch_size.min(connection.messages.len());
+            let messages = connection.messages.drain(0..count).collect();
+            save(&connection);
+            messages
+        }
+        _ => Vec::new(),
+    }
+}
+
 #[derive(Debug, Default, PartialEq, Deserialize)]
 pub struct Connections {}
 
@@ -31,7 +112,7 @@ unsafe impl Sync for Connections {}
 
 #[async_trait]
 impl ConnectionStorage for Connections {
-    async fn insert_message(&mut self, message: Message) {
+    async fn insert_message(&self, message: Message) {
         let dids = message.get_didcomm_header().to.to_vec();
         for did in &dids {
             self.insert_message_for(message.clone(), did.to_string())
@@ -39,51 +120,145 @@ impl ConnectionStorage for Connections {
         }
     }
 
-    async fn insert_message_for(&mut self, message: Message, did_to: String) {
-        console_log!("{}, {:?}", did_to, message);
-        let mut connection = match self.get(did_to.to_string()).await {
-            Some(connection) => connection.clone(),
-            None => Connection::new(did_to.to_string(), Default::default()),
-        };
-        connection.messages.push_back(message.clone());
-        let value = serde_json::to_value(&connection).unwrap();
-        put(did_to, value);
+    async fn insert_message_for(&self, message: Message, did_to: String) {
+        console_log!("{}, {}", did_to, message.get_didcomm_header().id);
+        let key = message_key(&did_to, Date::now().as_millis(), &message);
+        put(key, serde_json::to_value(&message).unwrap());
     }
 
-    async fn get_next(&mut self, did: String) -> Option<Message> {
-        let message = match self.get(did.to_string()).await {
-            Some(connection) => {
-                let mut connection: Connection = connection.clone();
-                let message = connection.messages.pop_front();
-                let value = serde_json::to_value(&connection).unwrap();
-                put(did, value);
-                message
+    async fn requeue(&self, messages: Vec<Message>, did_to: String) {
+        // millis 0 sorts before every queued message
+        for (index, message) in messages.iter().enumerate() {
+            let key = message_key(&did_to, index as u64, message);
+            put(key, serde_json::to_value(message).unwrap());
+        }
+    }
+
+    async fn get_next(&self, did: String) -> Option<Message> {
+        self.get_messages(did, 1)
+            .await
+            .and_then(|mut messages| messages.pop())
+    }
+
+    /// KV has no atomic take, so a message is read before it is deleted:
+    /// delivery is at least once, and two concurrent pickups may both serve
+    /// it. Recipients drop duplicates by message id.
+    async fn get_messages(&self, did: String, batch_size: usize) -> Option<Vec<Message>> {
+        let keys = message_keys(&did);
+        if keys.is_empty() && load(&did).is_none() {
+            return None;
+        }
+        let mut messages = take_legacy(&did, batch_size);
+        for key in keys {
+            if messages.len() >= batch_size {
+                break;
             }
-            None => None,
+            let message = load_message(&key);
+            delete(key);
+            match message {
+                Some(message) if !is_expired(&message) => messages.push(message),
+                _ => {}
+            }
+        }
+        Some(messages)
+    }
+
+    async fn get(&self, did: String) -> Option<Connection> {
+        let keys = message_keys(&did);
+        let mut connection = match load(&did) {
+            Some(connection) => connection,
+            None if keys.is_empty() => return None,
+            None => Connection::new(did.to_string(), Default::default()),
         };
-        message
+        for key in keys {
+            if let Some(message) = load_message(&key) {
+                connection.messages.push_back(message);
+            }
+        }
+        connection.remove_expired();
+        Some(connection)
     }
 
-    async fn get_messages(&mut self, did: String, batch_size: usize) -> Option<Vec<Message>> {
-        match self.get(did.to_string()).await {
-            Some(connection) => {
-                let mut connection: Connection = connection.clone();
-                let messages = connection
-                    .messages
-                    .drain(0..batch_size.min(connection.messages.len()));
-
-                let messages: Vec<Message> = messages.collect();
-                let value = serde_json::to_value(&connection).unwrap();
-                put(did, value);
-                Some(messages)
+    async fn list(&self) -> Vec<(String, usize)> {
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for key in list("") {
+            match key.strip_prefix(MESSAGES) {
+                Some(rest) => {
+                    if let Some((did, _)) = rest.rsplit_once('/') {
+                        *counts.entry(did.to_string()).or_default() += 1;
+                    }
+                }
+                None => {
+                    let legacy = load(&key).map_or(0, |connection| connection.messages.len());
+                    *counts.entry(key).or_default() += legacy;
+                }
             }
-            None => None,
         }
+        counts.into_iter().collect()
     }
 
-    async fn get(&self, did: String) -> Option<Connection> {
-        let did = did.to_string();
-        let connection: Option<Connection> = serde_json::from_value(get(did)).unwrap();
+    async fn purge(&self, did: String) -> usize {
+        let keys = message_keys(&did);
+        let mut purged = keys.len();
+        for key in keys {
+            delete(key);
+        }
+        if let Some(mut connection) = load(&did) {
+            purged += connection.messages.drain(..).count();
+            save(&connection);
+        }
+        purged
+    }
+
+    async fn remove(&self, did: String) -> Option<Connection> {
+        let connection = self.get(did.to_string()).await;
+        if connection.is_some() {
+            for key in message_keys(&did) {
+                delete(key);
+            }
+            delete(did);
+        }
         connection
     }
+
+    async fn set_device(&self, did: String, device: Option<DeviceInfo>) {
+        let mut connection = match load(&did) {
+            Some(connection) => connection,
+            None => Connection::new(did.to_string(), Default::default()),
+        };
+        connection.device = device;
+        save(&connection);
+    }
+
+    async fn get_device(&self, did: String) -> Option<DeviceInfo> {
+        load(&did).and_then(|connection| connection.device)
+    }
+
+    async fn migrate(&self, from: String, to: String) -> bool {
+        let keys = message_keys(&from);
+        let connection = load(&from);
+        if keys.is_empty() && connection.is_none() {
+            return false;
+        }
+        let prefix = messages_prefix(&from);
+        for key in keys {
+            let moved = format!("{}{}", messages_prefix(&to), &key[prefix.len()..]);
+            put(moved, get(key.to_string()));
+            delete(key);
+        }
+        if let Some(mut connection) = connection {
+            if let Some(existing) = load(&to) {
+                let mut messages = existing.messages;
+                messages.append(&mut connection.messages);
+                connection.messages = messages;
+                if connection.device.is_none() {
+                    connection.device = existing.device;
+                }
+            }
+            connection.did = to.to_string();
+            save(&connection);
+            delete(from);
+        }
+        true
+    }
 }
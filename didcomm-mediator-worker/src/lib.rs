@@ -1,25 +1,21 @@
-use base58::FromBase58;
-use did_key::{generate, DIDCore, KeyMaterial, X25519KeyPair, CONFIG_LD_PUBLIC};
+use did_key::{DIDCore, CONFIG_LD_PUBLIC};
+use didcomm_mediator::config::{CorsConfig, RateLimitConfig};
 use didcomm_mediator::connections::ConnectionStorage;
-use serde_json::{json, Value};
+use didcomm_mediator::ratelimit::RateLimitInterceptor;
+use serde_json::json;
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::sync::Arc;
 use wasm_bindgen::prelude::*;
 use worker::*;
 pub mod connections;
 pub mod utils;
-use async_mutex::Mutex;
-use didcomm_mediator::handler::{DidcommHandler, HandlerResponse};
-use didcomm_mediator::message::{has_return_route_all_header, sign_and_encrypt};
-use didcomm_mediator::protocols::didexchange::DidExchangeHandler;
+use didcomm_mediator::mediator::{Mediator, MediatorOutput};
+use didcomm_mediator::message::MediaType;
 use didcomm_mediator::protocols::didexchange::DidExchangeResponseBuilder;
-use didcomm_mediator::protocols::discoverfeatures::DiscoverFeaturesHandler;
-use didcomm_mediator::protocols::forward::ForwardBuilder;
-use didcomm_mediator::protocols::forward::ForwardHandler;
 use didcomm_mediator::protocols::invitation::InvitationBuilder;
-use didcomm_mediator::protocols::messagepickup::MessagePickupHandler;
-use didcomm_mediator::protocols::trustping::TrustPingHandler;
 use didcomm_mediator::service::Service;
-use didcomm_rs::Message;
+use didcomm_mediator::wallet::Wallet;
 
 fn log_request(req: &Request) {
     console_log!(
@@ -40,16 +36,79 @@ extern "C" {
 
     #[wasm_bindgen(static_method_of = KV)]
     pub async fn put(key: String, value: JsValue);
+
+    #[wasm_bindgen(static_method_of = KV)]
+    pub async fn delete(key: String);
+
+    /// One page of keys, `options` being `{prefix, cursor}`.
+    #[wasm_bindgen(static_method_of = KV)]
+    pub async fn list(options: JsValue) -> JsValue;
 }
 
-// source: https://github.com/rodneylab/hcaptcha-serverless-rust-worker/blob/main/src/lib.rs
-fn preflight_response(_headers: &worker::Headers, _cors_origin: &str) -> Result<Response> {
+/// `CORS` holds the JSON form of the mediator's `cors` config,
+/// `CORS_ORIGIN` a comma separated list of allowed origins.
+fn cors_config(ctx: &RouteContext<()>) -> CorsConfig {
+    if let Ok(cors) = ctx.var("CORS") {
+        if let Ok(config) = serde_json::from_str(&cors.to_string()) {
+            return config;
+        }
+    }
+    let mut config = CorsConfig::default();
+    if let Ok(origins) = ctx.var("CORS_ORIGIN") {
+        config.policy.allowed_origins = origins
+            .to_string()
+            .split(',')
+            .map(|origin| origin.trim().to_string())
+            .collect();
+    }
+    config
+}
+
+fn cors_headers(req: &Request, cors: &CorsConfig, preflight: bool) -> Result<worker::Headers> {
+    let origin = req.headers().get("Origin")?;
     let mut headers = worker::Headers::new();
-    headers.set("Access-Control-Allow-Origin", "*")?;
-    headers.set("Access-Control-Allow-Methods", "POST, GET, PATCH, OPTIONS")?;
-    headers.set("Access-Control-Allow-Headers", "*")?;
-    headers.set("Access-Control-Allow-Credentials", "true")?;
-    headers.set("Access-Control-Max-Age", "600")?;
+    for (name, value) in cors
+        .policy(&req.path())
+        .headers(origin.as_deref(), preflight)
+    {
+        headers.set(name, &value)?;
+    }
+    Ok(headers)
+}
+
+/// `RATE_LIMIT` holds the JSON form of the mediator's `rate_limit` config.
+fn rate_limit_config(ctx: &RouteContext<()>) -> Option<RateLimitConfig> {
+    serde_json::from_str(&ctx.var("RATE_LIMIT").ok()?.to_string()).ok()
+}
+
+thread_local! {
+    static MEDIATOR: RefCell<Option<Rc<Mediator>>> = RefCell::new(None);
+}
+
+/// The mediator lives as long as the isolate, so its resolver cache and
+/// rate limits carry over between requests.
+fn mediator(ctx: &RouteContext<()>) -> Rc<Mediator> {
+    MEDIATOR.with(|mediator| {
+        mediator
+            .borrow_mut()
+            .get_or_insert_with(|| {
+                let seed = ctx.secret("SEED").unwrap().to_string();
+                let connections: Arc<dyn ConnectionStorage> =
+                    Arc::new(connections::Connections::new());
+                let mut mediator = Mediator::new(Wallet::new(Some(seed)), connections);
+                if let Some(rate_limit) = rate_limit_config(ctx) {
+                    mediator =
+                        mediator.interceptor(Box::new(RateLimitInterceptor::new(rate_limit)));
+                }
+                Rc::new(mediator)
+            })
+            .clone()
+    })
+}
+
+// source: https://github.com/rodneylab/hcaptcha-serverless-rust-worker/blob/main/src/lib.rs
+fn preflight_response(req: &Request, cors: &CorsConfig) -> Result<Response> {
+    let headers = cors_headers(req, cors, true)?;
     Ok(Response::empty()
         .unwrap()
         .with_headers(headers)
@@ -63,20 +122,20 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
     let router = Router::new();
     router
         .get("/", |_, _| Response::ok("Mediator"))
-        .options("/didcomm", |req, _ctx| {
-            preflight_response(req.headers(), "")
+        .options("/didcomm", |req, ctx| {
+            preflight_response(&req, &cors_config(&ctx))
         })
-        .options("/invitation", |req, _ctx| {
-            preflight_response(req.headers(), "")
+        .options("/invitation", |req, ctx| {
+            preflight_response(&req, &cors_config(&ctx))
         })
-        .get_async("/invitation", |_req, ctx| async move {
+        .get_async("/invitation", |req, ctx| async move {
             let seed = ctx.secret("SEED").unwrap().to_string();
             let _ident = ctx.var("IDENT").unwrap().to_string();
             let ext_service = ctx.var("EXT_SERVICE").unwrap().to_string();
-            let key = generate::<X25519KeyPair>(Some(&seed.from_base58().unwrap()));
-
-            let mut did_doc = key.get_did_document(CONFIG_LD_PUBLIC);
-            did_doc.verification_method[0].private_key = None;
+            let did = Wallet::new(Some(seed)).did_key();
+            let did_doc = did_key::resolve(&did)
+                .unwrap()
+                .get_did_document(CONFIG_LD_PUBLIC);
 
             let did_exchange = DidExchangeResponseBuilder::new()
                 .did_doc(serde_json::to_value(&did_doc).unwrap())
@@ -93,20 +152,17 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
                 .build()
                 .unwrap();
 
-            let mut headers = worker::Headers::new();
-            headers.set("Access-Control-Allow-Methods", "GET")?;
-            headers.set("Access-Control-Allow-Origin", "*")?;
-            headers.set("Access-Control-Allow-Headers", "*")?;
-            headers.set("Access-Control-Allow-Credentials", "true")?;
+            let headers = cors_headers(&req, &cors_config(&ctx), false)?;
             let response = Response::from_json(&json!(invitation)).unwrap();
             Ok(response.with_headers(headers))
         })
         .get("/.well-known/did.json", |_req, ctx| {
             let seed = ctx.secret("SEED").unwrap().to_string();
-            let key = generate::<X25519KeyPair>(Some(&seed.from_base58().unwrap()));
+            let did = Wallet::new(Some(seed)).did_key();
             let ext_service = ctx.var("EXT_SERVICE").unwrap().to_string();
-            let mut did_doc = key.get_did_document(CONFIG_LD_PUBLIC);
-            did_doc.verification_method[0].private_key = None;
+            let did_doc = did_key::resolve(&did)
+                .unwrap()
+                .get_did_document(CONFIG_LD_PUBLIC);
             let mut did_doc = serde_json::to_value(&did_doc).unwrap();
             did_doc["service"] = serde_json::json!([
               {
@@ -118,88 +174,32 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
             Response::from_json(&did_doc)
         })
         .post_async("/didcomm", |mut req, ctx| async move {
-            let body: Value = match req.json().await {
+            let content_type = req.headers().get("Content-Type")?;
+            let media_type = match MediaType::from_header(content_type.as_deref()) {
+                Some(media_type) => media_type,
+                None => return Response::error("Unsupported media type", 415),
+            };
+            let body_str = match req.text().await {
                 Ok(res) => res,
                 Err(_) => return Response::error("Bad request", 400),
             };
-            let body_str = serde_json::to_string(&body).unwrap();
-            let connections: Arc<Mutex<Box<dyn ConnectionStorage>>> =
-                Arc::new(Mutex::new(Box::new(connections::Connections::new())));
-            let seed = ctx.secret("SEED").unwrap().to_string();
-            let key = generate::<X25519KeyPair>(Some(&seed.from_base58().unwrap()));
-            let mut headers = worker::Headers::new();
-            headers.set("Access-Control-Allow-Methods", "POST")?;
-            headers.set("Content-Type", "application/json")?;
-            headers.set("Access-Control-Allow-Origin", "*")?;
-            headers.set("Access-Control-Allow-Headers", "*")?;
-            headers.set("Access-Control-Allow-Credentials", "true")?;
-
-            let received =
-                match Message::receive(&body_str, Some(&key.private_key_bytes()), None, None) {
-                    Ok(received) => received,
-                    Err(error) => return Response::error(format!("{:?}", error), 400),
-                };
-
-            let handlers: Vec<Box<dyn DidcommHandler>> = vec![
-                Box::new(ForwardHandler::default()),
-                Box::new(DidExchangeHandler::default()),
-                Box::new(DiscoverFeaturesHandler::default()),
-                Box::new(TrustPingHandler::default()),
-                Box::new(MessagePickupHandler::default()),
-            ];
-
-            for handler in handlers {
-                match handler
-                    .handle(&received, Some(&key), Some(&connections))
-                    .await
-                {
-                    Ok(HandlerResponse::Skipped) => {}
-                    Ok(HandlerResponse::Processed) => {}
-                    Ok(HandlerResponse::Forward(receivers, message)) => {
-                        for receiver in receivers {
-                            let forward = ForwardBuilder::new()
-                                .did(receiver.to_string())
-                                .message(serde_json::to_string(&message).unwrap())
-                                .build()
-                                .unwrap();
-                            let mut locked_connections = connections.try_lock().unwrap();
-                            locked_connections
-                                .insert_message_for(forward.clone(), receiver.to_string())
-                                .await;
-                        }
-                    }
-                    Ok(HandlerResponse::Send(to, message)) => {
-                        match has_return_route_all_header(&received) {
-                            true => {
-                                let response = match sign_and_encrypt(
-                                    &message,
-                                    &key.get_did_document(Default::default()).id,
-                                    &to,
-                                    &key,
-                                )
-                                .await
-                                {
-                                    Ok(response) => response,
-                                    Err(error) => serde_json::to_value(error.to_string()).unwrap(),
-                                };
-                                let response = Response::from_json(&json!(response)).unwrap();
-                                return Ok(response.with_headers(headers));
-                            }
-                            false => {
-                                let mut locked_connections = connections.try_lock().unwrap();
-                                locked_connections.insert_message_for(*message, to).await;
-                            }
-                        }
-                    }
-                    Ok(HandlerResponse::Response(product)) => {
-                        let response = Response::from_json(&product).unwrap();
-                        return Ok(response.with_headers(headers));
-                    }
-                    Err(error) => return Response::error(format!("{:?}", error), 400),
+            let mut headers = cors_headers(&req, &cors_config(&ctx), false)?;
+            let mediator = mediator(&ctx);
+            match mediator.process(&body_str).await {
+                MediatorOutput::Response(product) => {
+                    headers.set("Content-Type", media_type.response_type().as_str())?;
+                    let response = Response::ok(product.to_string())?;
+                    Ok(response.with_headers(headers))
                 }
+                MediatorOutput::Empty => {
+                    headers.set("Content-Type", "application/json")?;
+                    let response = Response::ok(json!({}).to_string())?;
+                    Ok(response.with_headers(headers))
+                }
+                MediatorOutput::BadRequest(error) => Response::error(error, 400),
+                MediatorOutput::TooManyRequests => Response::error("rate limit exceeded", 429),
+                MediatorOutput::InternalError(error) => Response::error(error, 500),
             }
-            let response = Response::from_json(&json!({})).unwrap();
-            Ok(response.with_headers(headers))
         })
         .run(req, env)
         .await
@@ -1,44 +1,90 @@
 #[macro_use]
 extern crate rocket;
-use async_mutex::Mutex;
-use base58::{FromBase58, ToBase58};
-use did_key::{generate, DIDCore, KeyMaterial, X25519KeyPair, CONFIG_LD_PUBLIC};
-use didcomm_mediator::config::Config;
-use didcomm_mediator::connections::{ConnectionStorage, Connections};
+use clap::{Parser, Subcommand, ValueEnum};
+use did_key::{DIDCore, CONFIG_LD_PUBLIC};
+use didcomm_mediator::config::{Config, CorsConfig, RateLimit};
+use didcomm_mediator::connections::{Connection, ConnectionStorage, Connections};
 use didcomm_mediator::diddoc::DidDocBuilder;
 use didcomm_mediator::didweb::url_to_did_web;
-use didcomm_mediator::handler::{DidcommHandler, HandlerResponse};
-use didcomm_mediator::message::receive;
-use didcomm_mediator::message::{has_return_route_all_header, sign_and_encrypt};
-use didcomm_mediator::protocols::didexchange::{DidExchangeHandler, DidExchangeResponseBuilder};
-use didcomm_mediator::protocols::discoverfeatures::DiscoverFeaturesHandler;
-use didcomm_mediator::protocols::forward::{ForwardBuilder, ForwardHandler};
+#[cfg(all(feature = "keyfile", not(feature = "iota")))]
+use didcomm_mediator::keyfile::Keyfile;
+use didcomm_mediator::mediator::{Mediator, MediatorOutput};
+use didcomm_mediator::message::MediaType;
+#[cfg(feature = "metrics")]
+use didcomm_mediator::metrics::PrometheusMetrics;
+use didcomm_mediator::protocols::didexchange::DidExchangeResponseBuilder;
 use didcomm_mediator::protocols::invitation::InvitationBuilder;
-use didcomm_mediator::protocols::messagepickup::MessagePickupHandler;
-use didcomm_mediator::protocols::trustping::TrustPingHandler;
+#[cfg(feature = "webhooks")]
+use didcomm_mediator::push::WebhookPushNotifier;
+use didcomm_mediator::ratelimit::{RateLimitInterceptor, RateLimiter};
+use didcomm_mediator::registry::HandlerRegistry;
 use didcomm_mediator::service::Service;
+use didcomm_mediator::tenants::{check_tenant_name, Tenant, Tenants};
 use didcomm_mediator::wallet::Wallet;
+#[cfg(feature = "webhooks")]
+use didcomm_mediator::webhook::Webhooks;
+use didcomm_rs::Message;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::{SinkExt, StreamExt};
+use qrcode::render::unicode;
+use qrcode::QrCode;
+use rocket::data::Limits;
 use rocket::fairing::{Fairing, Info, Kind};
-use rocket::http::{Header, Status};
-use rocket::{response::Redirect, serde::json::Json, Request, Response, State};
+use rocket::http::{ContentType, Header, Method, Status};
+use rocket::mtls::Certificate;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{
+    response::Redirect, serde::json::Json, Build, Data, Orbit, Request, Response, Rocket, State,
+};
+use rocket_ws::{Channel, Message as WsMessage, WebSocket};
 use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::vec;
+use tokio::sync::RwLock;
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
 
 #[get("/", rank = 3)]
 fn index() -> Redirect {
     Redirect::to(uri!(invitation_endpoint))
 }
 
+#[derive(Default)]
+pub struct InvitationCache(RwLock<Option<Value>>);
+
 #[get("/invitation")]
-async fn invitation_endpoint(config: &State<Config>, wallet: &State<Wallet>) -> Json<Value> {
-    oob_invitation_endpoint(config, wallet).await
+async fn invitation_endpoint(
+    config: &State<Config>,
+    mediator: &State<Mediator>,
+    cache: &State<InvitationCache>,
+) -> Json<Value> {
+    if let Some(invitation) = cache.0.read().await.as_ref() {
+        return Json(invitation.clone());
+    }
+    let mut cached = cache.0.write().await;
+    let invitation = match cached.as_ref() {
+        Some(invitation) => invitation.clone(),
+        None => create_invitation(&config.ext_service, mediator.wallet()).await,
+    };
+    *cached = Some(invitation.clone());
+    Json(invitation)
 }
 
 #[post("/outofband/create-invitation")]
-async fn oob_invitation_endpoint(config: &State<Config>, wallet: &State<Wallet>) -> Json<Value> {
-    let mut did_doc = wallet.keypair().get_did_document(CONFIG_LD_PUBLIC);
-    did_doc.verification_method[0].private_key = None;
+async fn oob_invitation_endpoint(
+    config: &State<Config>,
+    mediator: &State<Mediator>,
+) -> Json<Value> {
+    Json(create_invitation(&config.ext_service, mediator.wallet()).await)
+}
+
+async fn create_invitation(ext_service: &str, wallet: &Wallet) -> Value {
+    let did_doc = did_key::resolve(&wallet.did_key())
+        .unwrap()
+        .get_did_document(CONFIG_LD_PUBLIC);
 
     let did_exchange = DidExchangeResponseBuilder::new()
         .did_doc(serde_json::to_value(&did_doc).unwrap())
@@ -46,18 +92,17 @@ async fn oob_invitation_endpoint(config: &State<Config>, wallet: &State<Wallet>)
         .build_request()
         .unwrap();
 
-    let mut services: Vec<Service> =
-        vec![
-            Service::new(wallet.did_key(), config.ext_service.to_string())
+    let mut services: Vec<Service> = vec![Service::new(wallet.did_key(), ext_service.to_string())
+        .await
+        .unwrap()];
+    #[cfg(feature = "iota")]
+    if let Some(did_iota) = wallet.did_iota() {
+        services.push(
+            Service::new(did_iota, ext_service.to_string())
                 .await
                 .unwrap(),
-        ];
-    #[cfg(feature = "iota")]
-    services.push(
-        Service::new(wallet.did_iota().unwrap(), config.ext_service.to_string())
-            .await
-            .unwrap(),
-    );
+        );
+    }
     let invitation = InvitationBuilder::new()
         .goal("to create a relationship".to_string())
         .goal_code("aries.rel.build".to_string())
@@ -66,12 +111,12 @@ async fn oob_invitation_endpoint(config: &State<Config>, wallet: &State<Wallet>)
         .build()
         .unwrap();
 
-    let response = serde_json::from_str(&invitation.as_raw_json().unwrap()).unwrap();
-    Json(response)
+    serde_json::from_str(&invitation.as_raw_json().unwrap()).unwrap()
 }
 
 #[get("/.well-known/did.json")]
-async fn did_web_endpoint(config: &State<Config>, wallet: &State<Wallet>) -> Json<Value> {
+async fn did_web_endpoint(config: &State<Config>, mediator: &State<Mediator>) -> Json<Value> {
+    let wallet = mediator.wallet();
     let ext_hostname = config.ext_hostname.to_string();
     let did_web = url_to_did_web(&ext_hostname);
 
@@ -79,7 +124,8 @@ async fn did_web_endpoint(config: &State<Config>, wallet: &State<Wallet>) -> Jso
     did_doc_builder
         .did(did_web)
         .endpoint(config.ext_service.to_string())
-        .keypair(wallet.keypair());
+        .keypair(did_key::resolve(&wallet.did_key()).unwrap())
+        .authentication(wallet.signing_keypair());
 
     #[cfg(feature = "iota")]
     {
@@ -100,101 +146,520 @@ async fn did_web_endpoint(config: &State<Config>, wallet: &State<Wallet>) -> Jso
     Json(did_doc)
 }
 
-#[options("/didcomm")]
-fn didcomm_options() -> Status {
-    Status::Ok
+#[derive(Default)]
+pub struct TenantInvitations(RwLock<HashMap<String, Value>>);
+
+#[get("/<tenant>/invitation")]
+async fn tenant_invitation_endpoint(
+    tenant: &str,
+    tenants: &State<Tenants>,
+    cache: &State<TenantInvitations>,
+) -> Option<Json<Value>> {
+    let tenant = tenants.get(tenant)?;
+    if let Some(invitation) = cache.0.read().await.get(&tenant.name) {
+        return Some(Json(invitation.clone()));
+    }
+    let mut cached = cache.0.write().await;
+    let invitation = match cached.get(&tenant.name) {
+        Some(invitation) => invitation.clone(),
+        None => create_invitation(&tenant.ext_service, tenant.mediator.wallet()).await,
+    };
+    cached.insert(tenant.name.to_string(), invitation.clone());
+    Some(Json(invitation))
+}
+
+#[get("/<tenant>/did.json")]
+fn tenant_did_web_endpoint(tenant: &str, tenants: &State<Tenants>) -> Option<Json<Value>> {
+    let tenant = tenants.get(tenant)?;
+    let did_doc = DidDocBuilder::new()
+        .did(tenant.did_web.to_string())
+        .endpoint(tenant.ext_service.to_string())
+        .keypair(did_key::resolve(&tenant.mediator.wallet().did_key()).unwrap())
+        .authentication(tenant.mediator.wallet().signing_keypair())
+        .build()
+        .unwrap();
+    Some(Json(did_doc))
+}
+
+#[options("/<_..>")]
+fn cors_preflight() -> Status {
+    Status::NoContent
 }
 
 #[post("/", format = "any", data = "<body>")]
 async fn root_didcomm_endpoint(
-    wallet: &State<Wallet>,
-    connections: &State<Arc<Mutex<Box<dyn ConnectionStorage>>>>,
-    body: Json<Value>,
-) -> Result<Json<Value>, Status> {
-    didcomm_endpoint(wallet, connections, body).await
+    limit: WithinRateLimit,
+    limits: &Limits,
+    content_type: Option<&ContentType>,
+    mediator: &State<Mediator>,
+    tenants: &State<Tenants>,
+    body: Data<'_>,
+) -> Result<(ContentType, String), Status> {
+    didcomm_endpoint(limit, limits, content_type, mediator, tenants, body).await
 }
 
+/// Serves the default identity and the tenants, picked by recipient kid.
 #[post("/didcomm", format = "any", data = "<body>")]
 async fn didcomm_endpoint(
-    wallet: &State<Wallet>,
-    connections: &State<Arc<Mutex<Box<dyn ConnectionStorage>>>>,
-    body: Json<Value>,
-) -> Result<Json<Value>, Status> {
-    let body_str = serde_json::to_string(&body.into_inner()).unwrap();
-    let connections: &Arc<Mutex<Box<dyn ConnectionStorage>>> = connections;
-
-    let received = match receive(
-        &body_str,
-        Some(&wallet.keypair().private_key_bytes()),
-        None,
-        None,
+    _limit: WithinRateLimit,
+    limits: &Limits,
+    content_type: Option<&ContentType>,
+    mediator: &State<Mediator>,
+    tenants: &State<Tenants>,
+    body: Data<'_>,
+) -> Result<(ContentType, String), Status> {
+    let (media_type, body) = read_envelope(limits, content_type, body).await?;
+    let mediator = match tenants.for_envelope(&body) {
+        Some(tenant) => &tenant.mediator,
+        None => mediator.inner(),
+    };
+    process_envelope(mediator, media_type, &body).await
+}
+
+#[post("/<tenant>/didcomm", format = "any", data = "<body>")]
+async fn tenant_didcomm_endpoint(
+    _limit: WithinRateLimit,
+    tenant: &str,
+    limits: &Limits,
+    content_type: Option<&ContentType>,
+    tenants: &State<Tenants>,
+    body: Data<'_>,
+) -> Result<(ContentType, String), Status> {
+    let tenant = tenants.get(tenant).ok_or(Status::NotFound)?;
+    let (media_type, body) = read_envelope(limits, content_type, body).await?;
+    process_envelope(&tenant.mediator, media_type, &body).await
+}
+
+async fn read_envelope(
+    limits: &Limits,
+    content_type: Option<&ContentType>,
+    body: Data<'_>,
+) -> Result<(MediaType, String), Status> {
+    let content_type = content_type.map(|content_type| content_type.to_string());
+    let media_type = match MediaType::from_header(content_type.as_deref()) {
+        Some(media_type) => media_type,
+        None => return Err(Status::UnsupportedMediaType),
+    };
+    let limit = limits.get("json").unwrap_or(Limits::JSON);
+    match body.open(limit).into_string().await {
+        Ok(body) if body.is_complete() => Ok((media_type, body.into_inner())),
+        Ok(_) => Err(Status::PayloadTooLarge),
+        Err(_) => Err(Status::BadRequest),
+    }
+}
+
+async fn process_envelope(
+    mediator: &Mediator,
+    media_type: MediaType,
+    body: &str,
+) -> Result<(ContentType, String), Status> {
+    match mediator.process(body).await {
+        MediatorOutput::Response(response) => {
+            let response_type = media_type.response_type().as_str();
+            Ok((
+                ContentType::parse_flexible(response_type).unwrap(),
+                response.to_string(),
+            ))
+        }
+        MediatorOutput::Empty => Ok((ContentType::JSON, "{}".to_string())),
+        MediatorOutput::BadRequest(_) => Err(Status::BadRequest),
+        MediatorOutput::TooManyRequests => Err(Status::TooManyRequests),
+        MediatorOutput::InternalError(_) => Err(Status::InternalServerError),
+    }
+}
+
+async fn next_pushed(outbox: &mut Option<UnboundedReceiver<Value>>) -> Option<Value> {
+    match outbox {
+        Some(outbox) => outbox.next().await,
+        None => futures::future::pending().await,
+    }
+}
+
+#[get("/ws")]
+fn ws_endpoint<'r>(
+    _limit: WithinRateLimit,
+    ws: WebSocket,
+    mediator: &'r State<Mediator>,
+) -> Channel<'r> {
+    mediator_channel(ws, mediator)
+}
+
+#[get("/<tenant>/ws")]
+fn tenant_ws_endpoint<'r>(
+    _limit: WithinRateLimit,
+    tenant: &str,
+    ws: WebSocket,
+    tenants: &'r State<Tenants>,
+) -> Option<Channel<'r>> {
+    let tenant = tenants.inner().get(tenant)?;
+    Some(mediator_channel(ws, &tenant.mediator))
+}
+
+fn mediator_channel(ws: WebSocket, mediator: &Mediator) -> Channel<'_> {
+    ws.channel(move |mut stream| {
+        Box::pin(async move {
+            let mut session: Option<(String, u64)> = None;
+            let mut outbox: Option<UnboundedReceiver<Value>> = None;
+            let result = loop {
+                tokio::select! {
+                    frame = stream.next() => match frame {
+                        Some(Ok(WsMessage::Text(raw))) => {
+                            // the sender `receive` authenticated by its skid
+                            let span = Mediator::span();
+                            let received = mediator.receive(&raw).instrument(span.clone()).await.and_then(|received| {
+                                let from = received.sender.clone();
+                                match &session {
+                                    Some((did, _)) if *did != from => {
+                                        Err(format!("socket is bound to {}", did))
+                                    }
+                                    _ => Ok((from, received)),
+                                }
+                            });
+                            let (from, received) = match received {
+                                Ok(received) => received,
+                                Err(error) => {
+                                    span.in_scope(|| tracing::warn!(%error, "rejected websocket frame"));
+                                    let error = serde_json::json!({ "error": error });
+                                    if let Err(error) = stream.send(WsMessage::Text(error.to_string())).await {
+                                        break Err(error);
+                                    }
+                                    continue;
+                                }
+                            };
+                            if session.is_none() {
+                                let (id, receiver) = mediator.live().connect(&from);
+                                outbox = Some(receiver);
+                                session = Some((from, id));
+                            }
+                            if let MediatorOutput::Response(response) = mediator.handle(&received).instrument(span).await {
+                                if let Err(error) = stream.send(WsMessage::Text(response.to_string())).await {
+                                    break Err(error);
+                                }
+                            }
+                        }
+                        Some(Ok(WsMessage::Close(_))) | None => break Ok(()),
+                        Some(Ok(_)) => {}
+                        Some(Err(error)) => break Err(error),
+                    },
+                    Some(pushed) = next_pushed(&mut outbox) => {
+                        if let Err(error) = stream.send(WsMessage::Text(pushed.to_string())).await {
+                            break Err(error);
+                        }
+                    }
+                }
+            };
+            if let Some((did, id)) = session {
+                mediator.live().close(&did, id);
+            }
+            result
+        })
+    })
+}
+
+#[get("/health")]
+fn health_endpoint() -> Json<Value> {
+    Json(serde_json::json!({"status": "ok"}))
+}
+
+fn check(ok: bool, error: Option<String>) -> Value {
+    match error {
+        Some(error) if !ok => serde_json::json!({"ok": false, "error": error}),
+        _ => serde_json::json!({ "ok": ok }),
+    }
+}
+
+#[get("/ready")]
+async fn ready_endpoint(
+    config: &State<Config>,
+    mediator: &State<Mediator>,
+) -> (Status, Json<Value>) {
+    let mut checks = serde_json::Map::new();
+    let wallet = mediator.wallet().is_loaded();
+    checks.insert("wallet".to_string(), check(wallet, None));
+
+    let storage = tokio::time::timeout(
+        std::time::Duration::from_secs(2),
+        mediator.connections().get(mediator.wallet().did_key()),
     )
     .await
-    {
-        Ok(received) => received,
-        Err(_) => return Err(Status::BadRequest),
+    .is_ok();
+    checks.insert(
+        "storage".to_string(),
+        check(storage, Some("timed out".to_string())),
+    );
+
+    #[cfg(feature = "iota")]
+    if config.ready_check_resolver.unwrap_or(false) {
+        let resolved = match config.did_iota.as_ref() {
+            Some(did) => {
+                let resolved = didcomm_mediator::resolver::resolve(did).await;
+                resolved.map(|_| ()).map_err(|error| format!("{:?}", error))
+            }
+            None => Err("did_iota not configured".to_string()),
+        };
+        checks.insert(
+            "resolver".to_string(),
+            check(resolved.is_ok(), resolved.err()),
+        );
+    }
+    #[cfg(not(feature = "iota"))]
+    let _ = config;
+
+    let ready = checks.values().all(|check| check["ok"] == true);
+    let status = if ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
     };
+    (
+        status,
+        Json(serde_json::json!({"ready": ready, "checks": checks})),
+    )
+}
 
-    let handlers: Vec<Box<dyn DidcommHandler>> = vec![
-        Box::new(ForwardHandler::default()),
-        Box::new(DidExchangeHandler::default()),
-        Box::new(DiscoverFeaturesHandler::default()),
-        Box::new(TrustPingHandler::default()),
-        Box::new(MessagePickupHandler::default()),
-    ];
+#[cfg(feature = "metrics")]
+#[get("/metrics")]
+fn metrics_endpoint(metrics: &State<Arc<PrometheusMetrics>>) -> String {
+    metrics.render()
+}
 
-    for handler in handlers {
-        let handled = {
-            let connections = connections.clone();
-            let handled = handler
-                .handle(&received, Some(&wallet.keypair()), Some(&connections))
-                .await;
-            handled.unwrap()
+pub struct Admin;
+
+fn api_key_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let config = match request.rocket().state::<Config>() {
+            Some(config) => config,
+            None => return Outcome::Error((Status::InternalServerError, ())),
         };
-        match handled {
-            HandlerResponse::Skipped => {}
-            HandlerResponse::Processed => {}
-            HandlerResponse::Forward(receivers, message) => {
-                for receiver in receivers {
-                    let forward = ForwardBuilder::new()
-                        .did(receiver.to_string())
-                        .message(serde_json::to_string(&message).unwrap())
-                        .build()
-                        .unwrap();
-                    let mut locked_connections = connections.try_lock().unwrap();
-                    locked_connections
-                        .insert_message_for(forward, receiver.to_string())
-                        .await;
-                    drop(locked_connections);
-                }
+        if let (Some(expected), Some(given)) = (
+            config.admin_api_key.as_ref(),
+            request.headers().get_one("X-API-Key"),
+        ) {
+            if api_key_matches(given, expected) {
+                return Outcome::Success(Admin);
             }
-            HandlerResponse::Send(to, message) => match has_return_route_all_header(&received) {
-                true => {
-                    let response = match sign_and_encrypt(
-                        &message,
-                        &wallet.keypair().get_did_document(Default::default()).id,
-                        &to,
-                        &wallet.keypair(),
-                    )
-                    .await
-                    {
-                        Ok(response) => response,
-                        Err(error) => serde_json::to_value(error.to_string()).unwrap(),
-                    };
-                    return Ok(Json(response));
-                }
-                false => {
-                    let mut locked_connections = connections.try_lock().unwrap();
-                    locked_connections.insert_message_for(*message, to).await;
-                }
-            },
-            HandlerResponse::Response(product) => return Ok(Json(product)),
+        }
+        if config.admin_mtls.unwrap_or(false)
+            && request.guard::<Certificate<'_>>().await.is_success()
+        {
+            return Outcome::Success(Admin);
+        }
+        Outcome::Error((Status::Unauthorized, ()))
+    }
+}
+
+fn message_metadata(message: &Message) -> Value {
+    let header = message.get_didcomm_header();
+    serde_json::json!({
+        "id": header.id,
+        "type": header.m_type,
+        "from": header.from,
+        "to": header.to,
+        "thid": header.thid,
+        "created_time": header.created_time,
+        "expires_time": header.expires_time,
+        "attachments": message.get_attachments().count(),
+    })
+}
+
+/// The mediator an admin request acts on, that of the tenant named by
+/// `?tenant=` or the default identity.
+fn admin_mediator<'a>(
+    mediator: &'a Mediator,
+    tenants: &'a Tenants,
+    tenant: Option<&str>,
+) -> Result<&'a Mediator, Status> {
+    match tenant {
+        Some(name) => tenants
+            .get(name)
+            .map(|tenant| &tenant.mediator)
+            .ok_or(Status::NotFound),
+        None => Ok(mediator),
+    }
+}
+
+#[get("/admin/connections?<tenant>")]
+async fn admin_connections(
+    _admin: Admin,
+    mediator: &State<Mediator>,
+    tenants: &State<Tenants>,
+    tenant: Option<&str>,
+) -> Result<Json<Value>, Status> {
+    let mediator = admin_mediator(mediator, tenants, tenant)?;
+    let connections: Vec<Value> = mediator
+        .connections()
+        .list()
+        .await
+        .into_iter()
+        .map(|(did, messages)| serde_json::json!({"did": did, "messages": messages}))
+        .collect();
+    Ok(Json(serde_json::json!(connections)))
+}
+
+#[get("/admin/connections/<did>/messages?<tenant>")]
+async fn admin_messages(
+    _admin: Admin,
+    mediator: &State<Mediator>,
+    tenants: &State<Tenants>,
+    did: &str,
+    tenant: Option<&str>,
+) -> Result<Json<Value>, Status> {
+    let mediator = admin_mediator(mediator, tenants, tenant)?;
+    match mediator.connections().get(did.to_string()).await {
+        Some(connection) => {
+            let messages: Vec<Value> = connection.messages.iter().map(message_metadata).collect();
+            Ok(Json(serde_json::json!(messages)))
+        }
+        None => Err(Status::NotFound),
+    }
+}
+
+#[delete("/admin/connections/<did>/messages?<tenant>")]
+async fn admin_purge(
+    _admin: Admin,
+    mediator: &State<Mediator>,
+    tenants: &State<Tenants>,
+    did: &str,
+    tenant: Option<&str>,
+) -> Result<Json<Value>, Status> {
+    let mediator = admin_mediator(mediator, tenants, tenant)?;
+    let purged = mediator.connections().purge(did.to_string()).await;
+    Ok(Json(serde_json::json!({"did": did, "purged": purged})))
+}
+
+#[delete("/admin/connections/<did>?<tenant>")]
+async fn admin_revoke(
+    _admin: Admin,
+    mediator: &State<Mediator>,
+    tenants: &State<Tenants>,
+    did: &str,
+    tenant: Option<&str>,
+) -> Result<Json<Value>, Status> {
+    let mediator = admin_mediator(mediator, tenants, tenant)?;
+    match mediator.connections().remove(did.to_string()).await {
+        Some(connection) => {
+            mediator.live().disconnect(did);
+            Ok(Json(serde_json::json!({
+                "did": did,
+                "revoked": true,
+                "purged": connection.messages.len(),
+            })))
+        }
+        None => Err(Status::NotFound),
+    }
+}
+
+#[post("/admin/invitation/rotate?<tenant>")]
+async fn admin_rotate_invitation(
+    _admin: Admin,
+    config: &State<Config>,
+    mediator: &State<Mediator>,
+    tenants: &State<Tenants>,
+    cache: &State<InvitationCache>,
+    tenant_cache: &State<TenantInvitations>,
+    tenant: Option<&str>,
+) -> Result<Json<Value>, Status> {
+    if let Some(name) = tenant {
+        let tenant = tenants.get(name).ok_or(Status::NotFound)?;
+        let invitation = create_invitation(&tenant.ext_service, tenant.mediator.wallet()).await;
+        tenant_cache
+            .0
+            .write()
+            .await
+            .insert(tenant.name.to_string(), invitation.clone());
+        return Ok(Json(invitation));
+    }
+    let invitation = create_invitation(&config.ext_service, mediator.wallet()).await;
+    *cache.0.write().await = Some(invitation.clone());
+    Ok(Json(invitation))
+}
+
+#[post("/admin/key/rotate?<tenant>")]
+async fn admin_rotate_key(
+    _admin: Admin,
+    config: &State<Config>,
+    mediator: &State<Mediator>,
+    tenants: &State<Tenants>,
+    cache: &State<InvitationCache>,
+    tenant: Option<&str>,
+) -> Result<Json<Value>, Status> {
+    // tenant DIDs are collected at startup, so their keys rotate by
+    // configuration and a restart
+    if let Some(name) = tenant {
+        tenants.get(name).ok_or(Status::NotFound)?;
+        tracing::warn!(tenant = %name, "refusing key rotation of a tenant");
+        return Err(Status::Conflict);
+    }
+    // a key only held in memory is gone on restart, and under iota the
+    // DID document would keep advertising the old key agreement key
+    if !mediator.wallet().persists_rotation() {
+        tracing::warn!("refusing key rotation without a keyfile");
+        return Err(Status::Conflict);
+    }
+    let retired = mediator.wallet().did_key();
+    let did = match mediator.wallet().rotate() {
+        Ok(did) => did,
+        Err(error) => {
+            tracing::error!(%error, "key rotation failed");
+            return Err(Status::InternalServerError);
+        }
+    };
+    *cache.0.write().await = Some(create_invitation(&config.ext_service, mediator.wallet()).await);
+    Ok(Json(serde_json::json!({"did": did, "retired": retired})))
+}
+
+/// Per client IP limit of the routes taking a `WithinRateLimit` guard.
+pub struct IpRateLimit {
+    limit: Option<RateLimit>,
+    limiter: RateLimiter,
+}
+
+struct RateLimited(Status);
+
+pub struct WithinRateLimit;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WithinRateLimit {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let (limit, limiter) = match request.rocket().state::<IpRateLimit>() {
+            Some(IpRateLimit {
+                limit: Some(limit),
+                limiter,
+            }) => (limit, limiter),
+            _ => return Outcome::Success(WithinRateLimit),
+        };
+        // charged once per request, even if the guard runs again
+        let RateLimited(status) = request.local_cache(|| match request.client_ip() {
+            Some(ip) if limiter.check(&ip.to_string(), limit) => RateLimited(Status::Ok),
+            Some(_) => RateLimited(Status::TooManyRequests),
+            None => {
+                tracing::warn!("rejected request without client ip");
+                RateLimited(Status::BadRequest)
+            }
+        });
+        if *status == Status::Ok {
+            Outcome::Success(WithinRateLimit)
+        } else {
+            Outcome::Error((*status, ()))
         }
     }
-    Ok(Json(serde_json::json!({})))
 }
 
-pub struct CORS;
+pub struct CORS(CorsConfig);
 
 #[rocket::async_trait]
 impl Fairing for CORS {
@@ -205,65 +670,528 @@ impl Fairing for CORS {
         }
     }
 
-    async fn on_response<'r>(&self, _request: &'r Request<'_>, response: &mut Response<'r>) {
-        response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
-        response.set_header(Header::new(
-            "Access-Control-Allow-Methods",
-            "POST, GET, PATCH, OPTIONS",
-        ));
-        response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
-        response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let preflight = request.method() == Method::Options;
+        let headers = self
+            .0
+            .policy(request.uri().path().as_str())
+            .headers(request.headers().get_one("Origin"), preflight);
+        for (name, value) in headers {
+            response.set_header(Header::new(name, value));
+        }
+    }
+}
+
+fn init_tracing(config: &Config) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    let _ = match config.log_format.as_deref() {
+        Some("json") => subscriber.json().try_init(),
+        _ => subscriber.try_init(),
+    };
+}
+
+#[derive(Parser)]
+#[command(name = "didcomm-mediator", version, about = "DIDComm v2 Mediator")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the mediator, the default without a subcommand
+    Serve,
+    /// Print a new random key seed
+    GenerateSeed,
+    /// Print the mediator's did:key, did:web and did:iota
+    ShowDid,
+    /// Print the out-of-band invitation
+    Invitation {
+        #[arg(long, value_enum, default_value_t = InvitationFormat::Json)]
+        format: InvitationFormat,
+    },
+    /// Write the connections stored at storage_path as JSON
+    ExportStorage {
+        /// Defaults to stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// The tenant whose storage to export, instead of the default identity
+        #[arg(long)]
+        tenant: Option<String>,
+    },
+    /// Merge connections from an export into storage_path
+    ImportStorage {
+        input: PathBuf,
+        /// The tenant whose storage to merge into, instead of the default identity
+        #[arg(long)]
+        tenant: Option<String>,
+    },
+    /// Rotate the key in the keyfile and print the old and new did:key
+    RotateKey,
+    /// Re-encrypt the keyfile at keyfile_path with a new password
+    #[cfg(all(feature = "keyfile", not(feature = "iota")))]
+    ChangePassword { new_password_file: PathBuf },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum InvitationFormat {
+    Json,
+    Url,
+    Qr,
+}
+
+fn load_config() -> Result<Config, Box<dyn Error>> {
+    let mut config: Config = rocket::Config::figment().extract()?;
+    config.load_secrets()?;
+    Ok(config)
+}
+
+fn generate_seed() -> String {
+    Wallet::default().seed().to_string()
+}
+
+/// Creates a secret file readable by its owner only.
+fn write_secret(path: &str, secret: &str) -> Result<(), Box<dyn Error>> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, secret.as_bytes())?;
+    Ok(())
+}
+
+/// Whether the wallet keys come from the keyfile rather than `key_seed`.
+fn uses_keyfile(config: &Config) -> bool {
+    cfg!(all(feature = "keyfile", not(feature = "iota")))
+        && config.keyfile_path.is_some()
+        && config.wallet_password.is_some()
+}
+
+/// The wallet `build` serves, from `key_seed` or the keyfile.
+async fn open_wallet(config: &Config) -> Result<Wallet, Box<dyn Error>> {
+    if config.key_seed.is_none() && !uses_keyfile(config) {
+        return Err("no key_seed or keyfile configured".into());
+    }
+    Wallet::new_from_config(config).await
+}
+
+/// did:key of a wallet, without the iota account `did_iota` already names.
+async fn did_key_of(config: &Config) -> Result<String, Box<dyn Error>> {
+    let config = Config {
+        wallet_path: None,
+        ..config.clone()
+    };
+    Ok(open_wallet(&config).await?.did_key())
+}
+
+async fn show_did(config: &Config) -> Result<String, Box<dyn Error>> {
+    let mut dids = vec![
+        format!("did:key  {}", did_key_of(config).await?),
+        format!("did:web  {}", url_to_did_web(&config.ext_hostname)),
+    ];
+    #[cfg(feature = "iota")]
+    if let Some(did_iota) = &config.did_iota {
+        dids.push(format!("did:iota {}", did_iota));
+    }
+    let mut names: Vec<&String> = config.tenants.keys().collect();
+    names.sort();
+    for name in names {
+        let tenant_config = config.tenant_wallet_config(name).unwrap();
+        let did_key = did_key_of(&tenant_config).await?;
+        dids.push(format!("{} did:key  {}", name, did_key));
+        let did_web = url_to_did_web(&config.tenant_url(name));
+        dids.push(format!("{} did:web  {}", name, did_web));
+    }
+    Ok(dids.join("\n"))
+}
+
+fn invitation_url(config: &Config, invitation: &Value) -> String {
+    format!(
+        "{}?oob={}",
+        config.ext_service,
+        base64::encode_config(invitation.to_string(), base64::URL_SAFE_NO_PAD)
+    )
+}
+
+async fn print_invitation(
+    config: &Config,
+    format: InvitationFormat,
+) -> Result<String, Box<dyn Error>> {
+    let wallet = open_wallet(config).await?;
+    let invitation = create_invitation(&config.ext_service, &wallet).await;
+    Ok(match format {
+        InvitationFormat::Json => serde_json::to_string_pretty(&invitation)?,
+        InvitationFormat::Url => invitation_url(config, &invitation),
+        InvitationFormat::Qr => QrCode::new(invitation_url(config, &invitation))?
+            .render::<unicode::Dense1x2>()
+            .build(),
+    })
+}
+
+fn load_storage(path: &str) -> Result<Vec<Connection>, Box<dyn Error>> {
+    match std::fs::read_to_string(path) {
+        Ok(json) => Ok(serde_json::from_str(&json)?),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(error) => Err(error.into()),
     }
 }
 
-#[launch]
-async fn rocket() -> _ {
-    let rocket = rocket::build();
-    let figment = rocket.figment();
-    let mut config: Config = figment.extract().expect("loading config");
-    let key = match config.key_seed.clone() {
-        Some(seed) => generate::<X25519KeyPair>(Some(&seed.from_base58().unwrap())),
-        None => {
-            let key = generate::<X25519KeyPair>(None);
-            let seed = key.private_key_bytes().to_base58();
-            println!("Generated Seed: {}", seed);
-            config.key_seed = Some(seed);
-            key
+async fn save_storage(path: &str, connections: &Connections) -> Result<(), Box<dyn Error>> {
+    let exported = connections.export().await;
+    write_storage(path, &serde_json::to_string(&exported)?)
+}
+
+/// Writes through a temporary file, so a crash never leaves half a snapshot.
+fn write_storage(path: &str, json: &str) -> Result<(), Box<dyn Error>> {
+    let temporary = format!("{}.tmp", path);
+    std::fs::write(&temporary, json)?;
+    std::fs::rename(temporary, path)?;
+    Ok(())
+}
+
+/// Exists while a server snapshots its connections to `path`.
+fn storage_lock(path: &str) -> String {
+    format!("{}.lock", path)
+}
+
+/// The storage_path of the default identity or of a tenant.
+fn storage_path(config: &Config, tenant: Option<&str>) -> Result<String, Box<dyn Error>> {
+    let path = match tenant {
+        Some(name) if !config.tenants.contains_key(name) => {
+            return Err(format!("no tenant {} configured", name).into())
         }
+        Some(name) => config.tenant_storage_path(name),
+        None => config.storage_path.clone(),
     };
-    config.did_key = Some(key.get_did_document(Default::default()).id);
+    path.ok_or_else(|| "no storage_path configured".into())
+}
+
+async fn import_storage(
+    config: &Config,
+    tenant: Option<&str>,
+    input: &Path,
+) -> Result<usize, Box<dyn Error>> {
+    let path = &storage_path(config, tenant)?;
+    let lock = storage_lock(path);
+    if Path::new(&lock).exists() {
+        return Err(format!(
+            "a running server owns {}, stop it first or remove {} if it crashed",
+            path, lock
+        )
+        .into());
+    }
+    let imported: Vec<Connection> = serde_json::from_str(&std::fs::read_to_string(input)?)?;
+    let count = imported.len();
+    let connections = Connections::new();
+    connections.import(load_storage(path)?).await;
+    connections.import(imported).await;
+    save_storage(path, &connections).await?;
+    Ok(count)
+}
+
+/// Rotates the key stored in the keyfile. Without a keyfile the new seed
+/// could only be printed, so the rotation is refused.
+fn rotate_key(config: &Config) -> Result<String, Box<dyn Error>> {
+    #[cfg(all(feature = "keyfile", not(feature = "iota")))]
+    if let (Some(path), Some(password)) = (&config.keyfile_path, &config.wallet_password) {
+        let wallet = Wallet::open_keyfile(path, password, config.key_seed.as_deref())?;
+        let old = wallet.did_key();
+        return Ok(format!(
+            "old did:key {}\nnew did:key {}",
+            old,
+            wallet.rotate()?
+        ));
+    }
+    let _ = config;
+    Err(
+        "rotate-key needs a keyfile, set keyfile_path and wallet_password in a build without iota"
+            .into(),
+    )
+}
+
+async fn run(command: Command) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::Serve => {
+            let _ = rocket().await.launch().await?;
+        }
+        Command::GenerateSeed => println!("{}", generate_seed()),
+        Command::ShowDid => println!("{}", show_did(&load_config()?).await?),
+        Command::Invitation { format } => {
+            println!("{}", print_invitation(&load_config()?, format).await?)
+        }
+        Command::ExportStorage { output, tenant } => {
+            let path = storage_path(&load_config()?, tenant.as_deref())?;
+            let exported = serde_json::to_string_pretty(&load_storage(&path)?)?;
+            match output {
+                Some(output) => std::fs::write(output, exported)?,
+                None => println!("{}", exported),
+            }
+        }
+        Command::ImportStorage { input, tenant } => {
+            let count = import_storage(&load_config()?, tenant.as_deref(), &input).await?;
+            println!("imported {} connections", count);
+        }
+        Command::RotateKey => println!("{}", rotate_key(&load_config()?)?),
+        #[cfg(all(feature = "keyfile", not(feature = "iota")))]
+        Command::ChangePassword { new_password_file } => {
+            let config = load_config()?;
+            let path = config.keyfile_path.ok_or("no keyfile_path configured")?;
+            let password = config
+                .wallet_password
+                .ok_or("no wallet_password configured")?;
+            let new_password = zeroize::Zeroizing::new(std::fs::read_to_string(new_password_file)?);
+            Keyfile::change_password(&path, &password, new_password.trim())?;
+            println!("changed the password of {}, update wallet_password", path);
+        }
+    }
+    Ok(())
+}
+
+#[rocket::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    run(Cli::parse().command.unwrap_or(Command::Serve)).await
+}
+
+async fn rocket() -> Rocket<Build> {
+    build(rocket::build()).await
+}
+
+/// How often a running server snapshots changed connections.
+const SNAPSHOT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+pub struct StorageSnapshot {
+    path: String,
+    connections: Arc<Connections>,
+}
+
+#[rocket::async_trait]
+impl Fairing for StorageSnapshot {
+    fn info(&self) -> Info {
+        Info {
+            name: "Save connections to storage_path while running and on shutdown",
+            kind: Kind::Liftoff | Kind::Shutdown,
+        }
+    }
+
+    async fn on_liftoff(&self, _rocket: &Rocket<Orbit>) {
+        let lock = storage_lock(&self.path);
+        if let Err(error) = std::fs::write(&lock, std::process::id().to_string()) {
+            tracing::error!(%lock, %error, "locking storage failed");
+        }
+        let path = self.path.to_string();
+        let connections = self.connections.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+            let mut saved = String::new();
+            loop {
+                interval.tick().await;
+                let json = serde_json::to_string(&connections.export().await).unwrap();
+                if json == saved {
+                    continue;
+                }
+                match write_storage(&path, &json) {
+                    Ok(()) => saved = json,
+                    Err(error) => tracing::error!(%path, %error, "saving connections failed"),
+                }
+            }
+        });
+    }
+
+    async fn on_shutdown(&self, _rocket: &Rocket<Orbit>) {
+        match save_storage(&self.path, &self.connections).await {
+            Ok(()) => tracing::info!(path = %self.path, "saved connections"),
+            Err(error) => tracing::error!(path = %self.path, %error, "saving connections failed"),
+        }
+        let _ = std::fs::remove_file(storage_lock(&self.path));
+    }
+}
+
+async fn build(rocket: Rocket<Build>) -> Rocket<Build> {
+    let mut config: Config = rocket.figment().extract().expect("loading config");
+    init_tracing(&config);
+    for path in config.missing_key_seed_files() {
+        write_secret(&path, &generate_seed()).expect("creating key_seed_file");
+        tracing::info!(%path, "generated key_seed_file");
+    }
+    config.load_secrets().expect("loading secrets");
+    if config.uses_demo_seed() && rocket.figment().profile() == rocket::Config::RELEASE_PROFILE {
+        panic!("refusing to start the release profile with the demo key_seed, set key_seed_file or MEDIATOR_KEY_SEED");
+    }
+    let figment = rocket
+        .figment()
+        .clone()
+        .merge(("limits.json", config.message_limits.max_envelope_size));
+    let rocket = rocket.configure(figment);
+    if config.key_seed.is_none() && !uses_keyfile(&config) {
+        panic!(
+            "no key_seed configured, set key_seed_file to have one generated, or MEDIATOR_KEY_SEED"
+        );
+    }
     let wallet = Wallet::new_from_config(&config).await.unwrap();
+    config.did_key = Some(wallet.did_key());
+    let mut tenant_wallets = vec![];
+    let names: Vec<String> = config.tenants.keys().cloned().collect();
+    for name in names {
+        check_tenant_name(&name).expect("configuring tenants");
+        let mut tenant_config = config.tenant_wallet_config(&name).unwrap();
+        if tenant_config.key_seed.is_none() && !uses_keyfile(&tenant_config) {
+            panic!(
+                "no key_seed configured for tenant {}, set its key_seed_file or keyfile_path",
+                name
+            );
+        }
+        let wallet = Wallet::new_from_config(&tenant_config)
+            .await
+            .expect("opening tenant wallet");
+        tenant_config.forget_secrets();
+        tenant_wallets.push((name, wallet));
+    }
+    config.forget_secrets();
     wallet.log();
 
-    let connections: Arc<Mutex<Box<dyn ConnectionStorage>>> =
-        Arc::new(Mutex::new(Box::new(Connections::new())));
+    #[cfg(feature = "metrics")]
+    let metrics = Arc::new(PrometheusMetrics::new());
+    let (mut rocket, connections) = open_connections(
+        rocket,
+        config.storage_path.clone(),
+        #[cfg(feature = "metrics")]
+        &metrics,
+    )
+    .await;
+    let mediator = configure_mediator(
+        Mediator::new(wallet, connections),
+        &config,
+        #[cfg(feature = "metrics")]
+        &metrics,
+    );
+    let mut tenants = Tenants::default();
+    for (name, wallet) in tenant_wallets {
+        if wallet.did_key() == mediator.wallet().did_key() {
+            panic!("tenant {} uses the key of the default identity", name);
+        }
+        let (attached, connections) = open_connections(
+            rocket,
+            config.tenant_storage_path(&name),
+            #[cfg(feature = "metrics")]
+            &metrics,
+        )
+        .await;
+        rocket = attached;
+        let mediator = configure_mediator(
+            Mediator::new(wallet, connections),
+            &config,
+            #[cfg(feature = "metrics")]
+            &metrics,
+        );
+        let tenant = Tenant::new(&name, &config, mediator);
+        tracing::info!(tenant = %name, did = %tenant.mediator.wallet().did_key(), did_web = %tenant.did_web, "serving tenant");
+        tenants.insert(tenant).expect("configuring tenants");
+    }
+    let rocket = rocket.manage(IpRateLimit {
+        limit: config.rate_limit.clone().unwrap_or_default().per_ip,
+        limiter: RateLimiter::new(),
+    });
+    #[cfg(feature = "metrics")]
+    let rocket = rocket.mount("/", routes![metrics_endpoint]).manage(metrics);
 
     rocket
-        .attach(CORS)
+        .attach(CORS(config.cors.clone()))
         .mount(
             "/",
             routes![
                 index,
                 invitation_endpoint,
-                didcomm_options,
+                cors_preflight,
                 root_didcomm_endpoint,
                 didcomm_endpoint,
+                ws_endpoint,
                 oob_invitation_endpoint,
-                did_web_endpoint
+                did_web_endpoint,
+                tenant_didcomm_endpoint,
+                tenant_invitation_endpoint,
+                tenant_ws_endpoint,
+                tenant_did_web_endpoint,
+                health_endpoint,
+                ready_endpoint,
+                admin_connections,
+                admin_messages,
+                admin_purge,
+                admin_revoke,
+                admin_rotate_invitation,
+                admin_rotate_key
             ],
         )
         .manage(config)
-        .manage(wallet)
-        .manage(connections)
+        .manage(mediator)
+        .manage(tenants)
+        .manage(InvitationCache::default())
+        .manage(TenantInvitations::default())
+}
+
+/// In-memory connections, restored from and saved to `storage_path`.
+async fn open_connections(
+    rocket: Rocket<Build>,
+    storage_path: Option<String>,
+    #[cfg(feature = "metrics")] metrics: &Arc<PrometheusMetrics>,
+) -> (Rocket<Build>, Arc<dyn ConnectionStorage>) {
+    #[cfg(feature = "metrics")]
+    let connections = Connections::new().instrumentation(metrics.clone());
+    #[cfg(not(feature = "metrics"))]
+    let connections = Connections::new();
+    let connections = Arc::new(connections);
+    let rocket = match storage_path {
+        Some(path) => {
+            connections
+                .import(load_storage(&path).expect("loading storage"))
+                .await;
+            rocket.attach(StorageSnapshot {
+                path,
+                connections: connections.clone(),
+            })
+        }
+        None => rocket,
+    };
+    (rocket, connections)
+}
+
+fn configure_mediator(
+    mediator: Mediator,
+    config: &Config,
+    #[cfg(feature = "metrics")] metrics: &Arc<PrometheusMetrics>,
+) -> Mediator {
+    let mediator = mediator
+        .registry(HandlerRegistry::from_config(config))
+        .limits(config.message_limits.clone());
+    #[cfg(feature = "metrics")]
+    let mediator = mediator.instrumentation(metrics.clone());
+    #[cfg(feature = "webhooks")]
+    let mediator = match config.webhooks.clone() {
+        Some(webhooks) => mediator.listener(Arc::new(Webhooks::new(webhooks))),
+        None => mediator,
+    };
+    let rate_limit = config.rate_limit.clone().unwrap_or_default();
+    let mediator = if rate_limit.per_did.is_some() || rate_limit.protocols.is_some() {
+        mediator.interceptor(Box::new(RateLimitInterceptor::new(rate_limit)))
+    } else {
+        mediator
+    };
+    #[cfg(feature = "webhooks")]
+    let mediator = match config.push_webhook.clone() {
+        Some(webhook) => mediator.push_notifier(Arc::new(WebhookPushNotifier::new(webhook))),
+        None => mediator,
+    };
+    mediator
 }
 
 #[cfg(test)]
 mod main_tests {
     use super::*;
-    use did_key::{Ed25519KeyPair, CONFIG_JOSE_PUBLIC};
+    use did_key::{generate, Ed25519KeyPair, KeyMaterial, X25519KeyPair, CONFIG_JOSE_PUBLIC};
     use didcomm_mediator::message::add_return_route_all_header;
     use didcomm_mediator::message::sign_and_encrypt;
     use didcomm_mediator::protocols::didexchange::DidExchangeResponseBuilder;
+    use didcomm_mediator::protocols::forward::ForwardBuilder;
     use didcomm_mediator::protocols::messagepickup::MessagePickupResponseBuilder;
     use didcomm_mediator::protocols::trustping::TrustPingResponseBuilder;
     use didcomm_rs::crypto::{CryptoAlgorithm, SignatureAlgorithm};
@@ -466,6 +1394,10 @@ mod main_tests {
         let req = req.body(serde_json::to_string(&request).unwrap());
         let response = req.dispatch().await;
         assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.content_type().unwrap().to_string(),
+            "application/didcomm-encrypted+json"
+        );
 
         let response_json = response.into_string().await.unwrap();
         let received = Message::receive(&response_json, Some(&key.private_key_bytes()), None, None);
@@ -746,4 +1678,719 @@ mod main_tests {
             println!("message {:?}", received);
         }
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_concurrent_forwards_and_pickups() {
+        const FORWARDS: usize = 200;
+        const PICKUPS: usize = 200;
+
+        let client = Arc::new(Client::untracked(rocket().await).await.unwrap());
+        let response = client.get("/invitation").dispatch().await;
+        let invitation: Message = response.into_json().await.unwrap();
+        let (_, services) = invitation
+            .get_application_params()
+            .find(|(key, _)| *key == "services")
+            .unwrap();
+        let services: Vec<Service> = serde_json::from_str(services).unwrap();
+        let mediator_did = services[0].id.replace("#didcomm", "");
+
+        let alice_key = generate::<X25519KeyPair>(None);
+        let alice_did = alice_key.get_did_document(Default::default()).id;
+        let bob_key = generate::<X25519KeyPair>(None);
+        let bob_did = bob_key.get_did_document(Default::default()).id;
+
+        let mut forwards = Vec::new();
+        for _ in 0..FORWARDS {
+            let ping = TrustPingResponseBuilder::new().build().unwrap();
+            let ping = sign_and_encrypt(&ping, &alice_did, &bob_did, &alice_key)
+                .await
+                .unwrap();
+            let forward = ForwardBuilder::new()
+                .message_value(ping)
+                .did(bob_did.to_string())
+                .build()
+                .unwrap();
+            let forward = sign_and_encrypt(&forward, &alice_did, &mediator_did, &alice_key)
+                .await
+                .unwrap();
+            forwards.push(serde_json::to_string(&forward).unwrap());
+        }
+
+        let pickup = MessagePickupResponseBuilder::new()
+            .did(bob_did.to_string())
+            .batch_size(5)
+            .build_batch_pickup()
+            .unwrap();
+        let pickup = sign_and_encrypt(&pickup, &bob_did, &mediator_did, &bob_key)
+            .await
+            .unwrap();
+        let pickup = serde_json::to_string(&pickup).unwrap();
+
+        let mut forward_tasks = Vec::new();
+        for forward in forwards {
+            let client = client.clone();
+            forward_tasks.push(tokio::spawn(async move {
+                let mut req = client.post("/didcomm");
+                req.add_header(ContentType::JSON);
+                req.body(forward).dispatch().await.status()
+            }));
+        }
+        let mut pickup_tasks = Vec::new();
+        for _ in 0..PICKUPS {
+            let client = client.clone();
+            let pickup = pickup.to_string();
+            pickup_tasks.push(tokio::spawn(async move {
+                let mut req = client.post("/didcomm");
+                req.add_header(ContentType::JSON);
+                let response = req.body(pickup).dispatch().await;
+                (response.status(), response.into_string().await.unwrap())
+            }));
+        }
+
+        let count_batch = |response: &str| {
+            Message::receive(response, Some(&bob_key.private_key_bytes()), None, None)
+                .unwrap()
+                .get_attachments()
+                .count()
+        };
+
+        for task in forward_tasks {
+            assert_eq!(task.await.unwrap(), Status::Ok);
+        }
+        let mut delivered = 0;
+        for task in pickup_tasks {
+            let (status, response) = task.await.unwrap();
+            assert_eq!(status, Status::Ok);
+            delivered += count_batch(&response);
+        }
+        loop {
+            let mut req = client.post("/didcomm");
+            req.add_header(ContentType::JSON);
+            let response = req.body(pickup.to_string()).dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            let received = count_batch(&response.into_string().await.unwrap());
+            if received == 0 {
+                break;
+            }
+            delivered += received;
+        }
+        assert_eq!(delivered, FORWARDS);
+    }
+
+    #[tokio::test]
+    async fn test_admin_endpoints() {
+        let figment = rocket::Config::figment().merge(("admin_api_key", "secret"));
+        let client = Client::tracked(build(rocket::custom(figment)).await)
+            .await
+            .unwrap();
+        let mediator = client.rocket().state::<Mediator>().unwrap();
+        mediator
+            .connections()
+            .insert_message_for(Message::new(), "did:key:test".to_string())
+            .await;
+
+        let response = client.get("/admin/connections").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client
+            .get("/admin/connections")
+            .header(Header::new("X-API-Key", "wrong"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = client
+            .get("/admin/connections")
+            .header(Header::new("X-API-Key", "secret"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let connections: Value = response.into_json().await.unwrap();
+        assert_eq!(
+            connections,
+            serde_json::json!([{"did": "did:key:test", "messages": 1}])
+        );
+
+        let response = client
+            .get("/admin/connections/did:key:test/messages")
+            .header(Header::new("X-API-Key", "secret"))
+            .dispatch()
+            .await;
+        let messages: Value = response.into_json().await.unwrap();
+        assert_eq!(messages.as_array().unwrap().len(), 1);
+        assert_eq!(messages[0]["attachments"], 0);
+
+        let response = client
+            .delete("/admin/connections/did:key:test/messages")
+            .header(Header::new("X-API-Key", "secret"))
+            .dispatch()
+            .await;
+        let purged: Value = response.into_json().await.unwrap();
+        assert_eq!(purged["purged"], 1);
+
+        let response = client
+            .delete("/admin/connections/did:key:test")
+            .header(Header::new("X-API-Key", "secret"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .delete("/admin/connections/did:key:test")
+            .header(Header::new("X-API-Key", "secret"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_admin_rotate_invitation() {
+        let figment = rocket::Config::figment().merge(("admin_api_key", "secret"));
+        let client = Client::tracked(build(rocket::custom(figment)).await)
+            .await
+            .unwrap();
+        let first: Value = client
+            .get("/invitation")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        let cached: Value = client
+            .get("/invitation")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(first, cached);
+
+        let response = client.post("/admin/invitation/rotate").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        let rotated: Value = client
+            .post("/admin/invitation/rotate")
+            .header(Header::new("X-API-Key", "secret"))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_ne!(first["id"], rotated["id"]);
+        let current: Value = client
+            .get("/invitation")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(current, rotated);
+    }
+
+    #[tokio::test]
+    async fn test_admin_rotate_key() {
+        let figment = rocket::Config::figment().merge(("admin_api_key", "secret"));
+        let client = Client::tracked(build(rocket::custom(figment)).await)
+            .await
+            .unwrap();
+        let response = client
+            .post("/admin/key/rotate")
+            .header(Header::new("X-API-Key", "secret"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Conflict);
+    }
+
+    #[cfg(all(feature = "keyfile", not(feature = "iota")))]
+    #[tokio::test]
+    async fn test_admin_rotate_key_keyfile() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let keyfile = dir.join("wallet.keys");
+        let figment = rocket::Config::figment()
+            .merge(("admin_api_key", "secret"))
+            .merge(("keyfile_path", keyfile.to_str().unwrap()));
+        let client = Client::tracked(build(rocket::custom(figment)).await)
+            .await
+            .unwrap();
+        let rotated: Value = client
+            .post("/admin/key/rotate")
+            .header(Header::new("X-API-Key", "secret"))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_ne!(rotated["did"], rotated["retired"]);
+
+        let invitation: Message = client
+            .get("/invitation")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        let (_, services) = invitation
+            .get_application_params()
+            .find(|(key, _)| *key == "services")
+            .unwrap();
+        let services: Vec<Service> = serde_json::from_str(services).unwrap();
+        assert_eq!(
+            services[0].id.replace("#didcomm", ""),
+            rotated["did"].as_str().unwrap()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_health_and_ready() {
+        let rocket = rocket();
+        let client = Client::tracked(rocket.await).await.unwrap();
+        let response = client.get("/health").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client.get("/ready").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let ready: Value = response.into_json().await.unwrap();
+        assert_eq!(ready["ready"], true);
+        assert_eq!(ready["checks"]["wallet"]["ok"], true);
+        assert_eq!(ready["checks"]["storage"]["ok"], true);
+        assert!(ready["checks"].get("resolver").is_none());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        let rocket = rocket();
+        let client = Client::tracked(rocket.await).await.unwrap();
+        client
+            .post("/didcomm")
+            .header(ContentType::JSON)
+            .body("{}")
+            .dispatch()
+            .await;
+        let response = client.get("/metrics").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let metrics = response.into_string().await.unwrap();
+        assert!(metrics.contains("didcomm_decrypt_failures_total 0"));
+        assert!(metrics.contains("didcomm_resolver_duration_seconds"));
+    }
+
+    #[tokio::test]
+    async fn test_ip_rate_limit() {
+        let figment = rocket::Config::figment().merge((
+            "rate_limit",
+            serde_json::json!({"per_ip": {"capacity": 1, "per_second": 0.0}}),
+        ));
+        let client = Client::tracked(build(rocket::custom(figment)).await)
+            .await
+            .unwrap();
+        let first: std::net::SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        let second: std::net::SocketAddr = "127.0.0.2:8000".parse().unwrap();
+        for _ in 0..2 {
+            let response = client.get("/health").remote(first).dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+        }
+        let response = client
+            .post("/didcomm")
+            .remote(first)
+            .header(ContentType::JSON)
+            .body("{}")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let response = client
+            .post("/didcomm")
+            .remote(first)
+            .header(ContentType::JSON)
+            .body("{}")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let response = client
+            .post("/didcomm")
+            .remote(second)
+            .header(ContentType::JSON)
+            .body("{}")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[tokio::test]
+    async fn test_envelope_limit() {
+        let figment = rocket::Config::figment().merge((
+            "message_limits",
+            serde_json::json!({"max_envelope_size": 64}),
+        ));
+        let client = Client::tracked(build(rocket::custom(figment)).await)
+            .await
+            .unwrap();
+        let response = client
+            .post("/didcomm")
+            .header(ContentType::JSON)
+            .body(serde_json::json!({"protected": "x".repeat(128)}).to_string())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "no key_seed configured for tenant acme")]
+    async fn test_tenant_without_seed() {
+        let figment = rocket::Config::figment().merge(("tenants", serde_json::json!({"acme": {}})));
+        build(rocket::custom(figment)).await;
+    }
+
+    #[tokio::test]
+    async fn test_tenants() {
+        let seed = generate_seed();
+        let tenant_did = Wallet::new(Some(seed.to_string())).did_key();
+        let figment = rocket::Config::figment()
+            .merge(("tenants", serde_json::json!({"acme": {"key_seed": seed}})));
+        let client = Client::tracked(build(rocket::custom(figment)).await)
+            .await
+            .unwrap();
+
+        let response = client.get("/acme/did.json").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let did_doc: Value = response.into_json().await.unwrap();
+        assert_eq!(did_doc["id"], "did:web:localhost%3A8000:acme");
+        let status = client.get("/other/did.json").dispatch().await.status();
+        assert_eq!(status, Status::NotFound);
+
+        let response = client.get("/acme/invitation").dispatch().await;
+        let invitation: Message = response.into_json().await.unwrap();
+        let (_, services) = invitation
+            .get_application_params()
+            .find(|(key, _)| *key == "services")
+            .unwrap();
+        let services: Vec<Service> = serde_json::from_str(services).unwrap();
+        assert_eq!(services[0].id, format!("{}#didcomm", tenant_did));
+        assert_eq!(
+            services[0].service_endpoint,
+            "http://localhost:8000/acme/didcomm"
+        );
+
+        let key = generate::<X25519KeyPair>(None);
+        let did_from = key.get_did_document(Default::default()).id;
+        let ping = add_return_route_all_header(TrustPingResponseBuilder::new().build().unwrap());
+        let request = sign_and_encrypt(&ping, &did_from, &tenant_did, &key)
+            .await
+            .unwrap();
+        let response = client
+            .post("/acme/didcomm")
+            .header(ContentType::JSON)
+            .body(request.to_string())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let response_json = response.into_string().await.unwrap();
+        let received =
+            Message::receive(&response_json, Some(&key.private_key_bytes()), None, None).unwrap();
+        assert_eq!(received.get_didcomm_header().from, Some(tenant_did));
+
+        let response = client
+            .post("/other/didcomm")
+            .header(ContentType::JSON)
+            .body(request.to_string())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_tenant_admin() {
+        let figment = rocket::Config::figment()
+            .merge(("admin_api_key", "secret"))
+            .merge((
+                "tenants",
+                serde_json::json!({"acme": {"key_seed": generate_seed()}}),
+            ));
+        let client = Client::tracked(build(rocket::custom(figment)).await)
+            .await
+            .unwrap();
+        let tenants = client.rocket().state::<Tenants>().unwrap();
+        tenants
+            .get("acme")
+            .unwrap()
+            .mediator
+            .connections()
+            .insert_message_for(Message::new(), "did:key:test".to_string())
+            .await;
+        let api_key = Header::new("X-API-Key", "secret");
+
+        let response = client
+            .get("/admin/connections")
+            .header(api_key.clone())
+            .dispatch()
+            .await;
+        let connections: Value = response.into_json().await.unwrap();
+        assert_eq!(connections, serde_json::json!([]));
+        let response = client
+            .get("/admin/connections?tenant=acme")
+            .header(api_key.clone())
+            .dispatch()
+            .await;
+        let connections: Value = response.into_json().await.unwrap();
+        assert_eq!(
+            connections,
+            serde_json::json!([{"did": "did:key:test", "messages": 1}])
+        );
+        let response = client
+            .get("/admin/connections?tenant=other")
+            .header(api_key.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        let response = client
+            .delete("/admin/connections/did:key:test/messages?tenant=acme")
+            .header(api_key.clone())
+            .dispatch()
+            .await;
+        let purged: Value = response.into_json().await.unwrap();
+        assert_eq!(purged["purged"], 1);
+
+        let first: Value = client
+            .get("/acme/invitation")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        let rotated: Value = client
+            .post("/admin/invitation/rotate?tenant=acme")
+            .header(api_key.clone())
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_ne!(first["id"], rotated["id"]);
+        let current: Value = client
+            .get("/acme/invitation")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(current, rotated);
+
+        let response = client
+            .post("/admin/key/rotate?tenant=acme")
+            .header(api_key)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Conflict);
+
+        let config = client.rocket().state::<Config>().unwrap();
+        assert!(storage_path(config, Some("other")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let figment = rocket::Config::figment().merge((
+            "cors",
+            serde_json::json!({
+                "allowed_origins": ["https://wallet.example"],
+                "routes": {"/admin": {"allowed_origins": []}},
+            }),
+        ));
+        let client = Client::tracked(build(rocket::custom(figment)).await)
+            .await
+            .unwrap();
+        let origin = Header::new("Origin", "https://wallet.example");
+
+        let response = client
+            .options("/didcomm")
+            .header(origin.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NoContent);
+        let headers = response.headers();
+        assert_eq!(
+            headers.get_one("Access-Control-Allow-Origin"),
+            Some("https://wallet.example")
+        );
+        assert_eq!(headers.get_one("Access-Control-Max-Age"), Some("600"));
+        assert_eq!(headers.get_one("Access-Control-Allow-Credentials"), None);
+
+        let response = client
+            .get("/health")
+            .header(Header::new("Origin", "https://evil.example"))
+            .dispatch()
+            .await;
+        assert_eq!(
+            response.headers().get_one("Access-Control-Allow-Origin"),
+            None
+        );
+
+        let response = client
+            .options("/admin/connections")
+            .header(origin)
+            .dispatch()
+            .await;
+        assert_eq!(
+            response.headers().get_one("Access-Control-Allow-Origin"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "demo key_seed")]
+    async fn test_release_refuses_demo_seed() {
+        let figment = rocket::Config::figment().select(rocket::Config::RELEASE_PROFILE);
+        build(rocket::custom(figment)).await;
+    }
+
+    #[tokio::test]
+    async fn test_show_did() {
+        let config = Config {
+            ext_hostname: "https://mediator.example".to_string(),
+            ..Default::default()
+        };
+        let dids = show_did(&config).await.unwrap();
+        assert!(dids.contains(&Wallet::new(config.key_seed.clone()).did_key()));
+        assert!(dids.contains("did:web:mediator.example"));
+        assert!(show_did(&Config {
+            key_seed: None,
+            ..Default::default()
+        })
+        .await
+        .is_err());
+    }
+
+    #[cfg(all(feature = "keyfile", not(feature = "iota")))]
+    #[tokio::test]
+    async fn test_show_did_keyfile() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let keyfile_path = |name: &str| Some(dir.join(name).to_str().unwrap().to_string());
+        let mut config = Config {
+            ext_hostname: "https://mediator.example".to_string(),
+            key_seed: None,
+            keyfile_path: keyfile_path("wallet.keys"),
+            ..Default::default()
+        };
+        config.tenants.insert(
+            "acme".to_string(),
+            didcomm_mediator::config::TenantConfig {
+                keyfile_path: keyfile_path("acme.keys"),
+                ..Default::default()
+            },
+        );
+        let dids = show_did(&config).await.unwrap();
+        let wallet = Wallet::new_from_config(&config).await.unwrap();
+        assert!(dids.contains(&format!("did:key  {}", wallet.did_key())));
+        let tenant = Wallet::new_from_config(&config.tenant_wallet_config("acme").unwrap())
+            .await
+            .unwrap();
+        assert!(dids.contains(&format!("acme did:key  {}", tenant.did_key())));
+
+        let invitation = print_invitation(&config, InvitationFormat::Json)
+            .await
+            .unwrap();
+        assert!(invitation.contains(&wallet.did_key()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_invitation_url() {
+        let config = Config {
+            ext_service: "https://mediator.example".to_string(),
+            ..Default::default()
+        };
+        let invitation = serde_json::json!({"type": "invitation"});
+        let url = invitation_url(&config, &invitation);
+        let oob = url.strip_prefix("https://mediator.example?oob=").unwrap();
+        let decoded = base64::decode_config(oob, base64::URL_SAFE_NO_PAD).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&decoded).unwrap(),
+            invitation
+        );
+    }
+
+    #[test]
+    fn test_write_secret() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("seed");
+        let path = path.to_str().unwrap();
+        write_secret(path, "secret").unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "secret");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(write_secret(path, "other").is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_import_storage() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let storage_path = dir.join("storage.json");
+        let export_path = dir.join("export.json");
+        let config = Config {
+            storage_path: Some(storage_path.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+
+        let connections = Connections::new();
+        connections
+            .insert_message(Message::new().to(&["did:key:test"]))
+            .await;
+        save_storage(export_path.to_str().unwrap(), &connections)
+            .await
+            .unwrap();
+        assert_eq!(
+            import_storage(&config, None, &export_path).await.unwrap(),
+            1
+        );
+        assert_eq!(
+            import_storage(&config, None, &export_path).await.unwrap(),
+            1
+        );
+
+        let stored = load_storage(config.storage_path.as_ref().unwrap()).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].messages.len(), 2);
+
+        std::fs::write(storage_lock(config.storage_path.as_ref().unwrap()), "1").unwrap();
+        assert!(import_storage(&config, None, &export_path).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_media_types() {
+        let client = Client::tracked(rocket().await).await.unwrap();
+        for media_type in [
+            "application/didcomm-plain+json",
+            "application/didcomm-signed+json",
+            "text/plain",
+        ] {
+            let response = client
+                .post("/didcomm")
+                .header(ContentType::parse_flexible(media_type).unwrap())
+                .body("{}")
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::UnsupportedMediaType);
+        }
+        let response = client.post("/didcomm").body("{}").dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest);
+        for media_type in [
+            "application/didcomm-encrypted+json",
+            "application/ssi-agent-wire",
+        ] {
+            let response = client
+                .post("/didcomm")
+                .header(ContentType::parse_flexible(media_type).unwrap())
+                .body("{}")
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::BadRequest);
+        }
+    }
 }
@@ -1,15 +1,142 @@
+use crate::wallet::RetiredKey;
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use zeroize::Zeroizing;
+
+/// Seed of the example configuration, never to be used outside development.
+pub const DEMO_KEY_SEED: &str = "293WPZ2PJQmNFN3MCMu49RM6ukVEQkfM1aJp9gJ8JhAs";
+pub const KEY_SEED_ENV: &str = "MEDIATOR_KEY_SEED";
+pub const WALLET_PASSWORD_ENV: &str = "MEDIATOR_WALLET_PASSWORD";
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(default)]
+pub struct MessageLimits {
+    pub max_envelope_size: usize,
+    pub max_attachment_size: usize,
+    pub max_attachments: usize,
+    /// Most messages a pickup `batch` returns, whatever `batch_size` asks for.
+    pub max_batch_size: usize,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        MessageLimits {
+            max_envelope_size: 1024 * 1024,
+            max_attachment_size: 512 * 1024,
+            max_attachments: 32,
+            max_batch_size: 100,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+pub struct RateLimit {
+    pub capacity: u32,
+    pub per_second: f64,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Clone)]
+pub struct RateLimitConfig {
+    pub per_ip: Option<RateLimit>,
+    pub per_did: Option<RateLimit>,
+    pub protocols: Option<HashMap<String, RateLimit>>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(default)]
+pub struct CorsPolicy {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age: Option<u64>,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        CorsPolicy {
+            allowed_origins: vec!["*".to_string()],
+            allowed_methods: vec!["GET".to_string(), "POST".to_string(), "OPTIONS".to_string()],
+            allowed_headers: vec!["Content-Type".to_string()],
+            allow_credentials: false,
+            max_age: Some(600),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Clone)]
+#[serde(default)]
+pub struct CorsConfig {
+    #[serde(flatten)]
+    pub policy: CorsPolicy,
+    /// Policies for paths starting with the given prefix, the longest prefix wins.
+    pub routes: HashMap<String, CorsPolicy>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub secret: String,
+    pub events: Option<Vec<String>>,
+    pub retries: Option<u32>,
+    pub backoff_ms: Option<u64>,
+}
+
+/// Another mediator identity served by the same process, under `/{tenant}`.
+#[derive(Default, PartialEq, Deserialize, Clone)]
+#[serde(default)]
+pub struct TenantConfig {
+    pub key_seed: Option<String>,
+    pub key_seed_file: Option<String>,
+    pub retired_keys: Option<Vec<RetiredKey>>,
+    /// Encrypted keyfile, used by builds without iota. Shares the
+    /// `wallet_password` of the mediator.
+    pub keyfile_path: Option<String>,
+    /// Stronghold snapshot of the iota account, iota is off without one.
+    pub wallet_path: Option<String>,
+    #[cfg(feature = "iota")]
+    pub did_iota: Option<String>,
+    /// Defaults to `{ext_hostname}/{tenant}/didcomm`.
+    pub ext_service: Option<String>,
+    /// Defaults to `storage_path` with the tenant name before the extension.
+    pub storage_path: Option<String>,
+}
+
 #[derive(PartialEq, Deserialize, Clone)]
 pub struct Config {
     pub ident: String,
     pub ext_hostname: String,
     pub ext_service: String,
+    /// Stronghold snapshot of the iota account.
     pub wallet_path: Option<String>,
+    /// Encrypted keyfile, used by builds without iota.
+    pub keyfile_path: Option<String>,
     pub wallet_password: Option<String>,
+    pub wallet_password_file: Option<String>,
     pub key_seed: Option<String>,
+    pub key_seed_file: Option<String>,
+    pub retired_keys: Option<Vec<RetiredKey>>,
+    pub key_grace_period: Option<i64>,
     pub did_key: Option<String>,
     #[cfg(feature = "iota")]
     pub did_iota: Option<String>,
+    pub protocols: Option<Vec<String>>,
+    pub disabled_protocols: Option<Vec<String>>,
+    pub admin_api_key: Option<String>,
+    pub admin_mtls: Option<bool>,
+    pub ready_check_resolver: Option<bool>,
+    pub log_format: Option<String>,
+    pub webhooks: Option<Vec<WebhookConfig>>,
+    pub push_webhook: Option<WebhookConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub storage_path: Option<String>,
+    #[serde(default)]
+    pub message_limits: MessageLimits,
+    #[serde(default)]
+    pub cors: CorsConfig,
+    #[serde(default)]
+    pub tenants: HashMap<String, TenantConfig>,
 }
 
 impl Default for Config {
@@ -19,11 +146,176 @@ impl Default for Config {
             ext_hostname: "".to_string(),
             ext_service: "".to_string(),
             wallet_path: Some("wallet.hold.example".to_string()),
+            keyfile_path: None,
             wallet_password: Some("changeme".to_string()),
-            key_seed: Some("293WPZ2PJQmNFN3MCMu49RM6ukVEQkfM1aJp9gJ8JhAs".to_string()),
+            wallet_password_file: None,
+            key_seed: Some(DEMO_KEY_SEED.to_string()),
+            key_seed_file: None,
+            retired_keys: None,
+            key_grace_period: None,
             did_key: Some("did:key:z6LSp5C8TjVvzJx3Kh5MFcdkHit6CVKTQ9RmTr3jLyE77BfH".to_string()),
             #[cfg(feature = "iota")]
             did_iota: Some("did:iota:11PwbeZDPtksuh5rTojk7eALu7R7adYQkBakt49tQE7".to_string()),
+            protocols: None,
+            disabled_protocols: None,
+            admin_api_key: None,
+            admin_mtls: None,
+            ready_check_resolver: None,
+            log_format: None,
+            webhooks: None,
+            push_webhook: None,
+            rate_limit: None,
+            storage_path: None,
+            message_limits: MessageLimits::default(),
+            cors: CorsConfig::default(),
+            tenants: HashMap::new(),
+        }
+    }
+}
+
+fn tenant_key_seed_env(name: &str) -> String {
+    format!(
+        "{}_{}",
+        KEY_SEED_ENV,
+        name.to_ascii_uppercase().replace('-', "_")
+    )
+}
+
+fn read_secret(path: &str) -> Result<String, Box<dyn Error>> {
+    let contents = Zeroizing::new(std::fs::read_to_string(path)?);
+    Ok(contents.trim().to_string())
+}
+
+fn load_secret(
+    env: &str,
+    file: &Option<String>,
+    inline: &mut Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    if let Ok(secret) = std::env::var(env) {
+        *inline = Some(secret);
+    } else if let Some(path) = file {
+        *inline = Some(read_secret(path)?);
+    }
+    Ok(())
+}
+
+fn missing_secret_file(env: &str, file: &Option<String>) -> Option<String> {
+    match file {
+        Some(path) if std::env::var(env).is_err() && !std::path::Path::new(path).exists() => {
+            Some(path.to_string())
+        }
+        _ => None,
+    }
+}
+
+impl Config {
+    /// `key_seed_file`s of the mediator and its tenants that do not exist
+    /// yet and are not overridden by the environment.
+    pub fn missing_key_seed_files(&self) -> Vec<String> {
+        let mut missing: Vec<String> = missing_secret_file(KEY_SEED_ENV, &self.key_seed_file)
+            .into_iter()
+            .collect();
+        for (name, tenant) in self.tenants.iter() {
+            missing.extend(missing_secret_file(
+                &tenant_key_seed_env(name),
+                &tenant.key_seed_file,
+            ));
         }
+        missing
+    }
+
+    /// Resolves `key_seed` and `wallet_password`: the environment wins over
+    /// the `*_file` settings, which win over inline values. Tenant seeds are
+    /// read from `MEDIATOR_KEY_SEED_{TENANT}`.
+    pub fn load_secrets(&mut self) -> Result<(), Box<dyn Error>> {
+        load_secret(KEY_SEED_ENV, &self.key_seed_file, &mut self.key_seed)?;
+        for (name, tenant) in self.tenants.iter_mut() {
+            load_secret(
+                &tenant_key_seed_env(name),
+                &tenant.key_seed_file,
+                &mut tenant.key_seed,
+            )?;
+        }
+        load_secret(
+            WALLET_PASSWORD_ENV,
+            &self.wallet_password_file,
+            &mut self.wallet_password,
+        )
+    }
+
+    pub fn uses_demo_seed(&self) -> bool {
+        self.key_seed.as_deref() == Some(DEMO_KEY_SEED)
+            || self
+                .tenants
+                .values()
+                .any(|tenant| tenant.key_seed.as_deref() == Some(DEMO_KEY_SEED))
+    }
+
+    /// Drops and zeroizes the secrets once the wallet holds them.
+    pub fn forget_secrets(&mut self) {
+        drop(self.key_seed.take().map(Zeroizing::new));
+        drop(self.wallet_password.take().map(Zeroizing::new));
+        // retired keys zeroize themselves
+        self.retired_keys = None;
+        for tenant in self.tenants.values_mut() {
+            drop(tenant.key_seed.take().map(Zeroizing::new));
+            tenant.retired_keys = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_files() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let seed_file = dir.join("seed");
+        std::fs::write(&seed_file, "seed-from-file\n").unwrap();
+
+        let mut config = Config {
+            key_seed_file: Some(seed_file.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        assert!(config.missing_key_seed_files().is_empty());
+        assert!(config.uses_demo_seed());
+        config.load_secrets().unwrap();
+        assert_eq!(config.key_seed.as_deref(), Some("seed-from-file"));
+        assert_eq!(config.wallet_password.as_deref(), Some("changeme"));
+        assert!(!config.uses_demo_seed());
+
+        config.tenants.insert(
+            "acme".to_string(),
+            TenantConfig {
+                key_seed_file: Some(seed_file.to_str().unwrap().to_string()),
+                ..Default::default()
+            },
+        );
+        config.load_secrets().unwrap();
+        assert_eq!(
+            config.tenants["acme"].key_seed.as_deref(),
+            Some("seed-from-file")
+        );
+
+        config.forget_secrets();
+        assert_eq!(config.key_seed, None);
+        assert_eq!(config.wallet_password, None);
+        assert_eq!(config.tenants["acme"].key_seed, None);
+
+        let missing = dir.join("missing").to_str().unwrap().to_string();
+        let config = Config {
+            key_seed_file: Some(missing.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(config.missing_key_seed_files(), vec![missing]);
+
+        let mut config = Config {
+            wallet_password_file: Some(dir.join("missing").to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        assert!(config.load_secrets().is_err());
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -1,8 +1,17 @@
+use crate::instrumentation::{Instrumentation, NoInstrumentation};
+use async_mutex::Mutex;
 use async_trait::async_trait;
+use chrono::Utc;
 use didcomm_rs::Message;
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::collections::VecDeque;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tracing::debug;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub enum ConnectionEndpoint {
@@ -16,11 +25,19 @@ impl Default for ConnectionEndpoint {
     }
 }
 
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct DeviceInfo {
+    pub device_token: String,
+    pub device_platform: String,
+}
+
 #[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Connection {
     pub did: String,
     pub endpoint: ConnectionEndpoint,
     pub messages: VecDeque<Message>,
+    #[serde(default)]
+    pub device: Option<DeviceInfo>,
 }
 
 impl Connection {
@@ -29,8 +46,20 @@ impl Connection {
             did,
             endpoint,
             messages: VecDeque::default(),
+            device: None,
         }
     }
+
+    pub fn remove_expired(&mut self) {
+        self.messages.retain(|message| !is_expired(message));
+    }
+}
+
+pub fn is_expired(message: &Message) -> bool {
+    match message.get_didcomm_header().expires_time {
+        Some(expires_time) => expires_time <= Utc::now().timestamp() as u64,
+        None => false,
+    }
 }
 
 unsafe impl Send for Connection {}
@@ -38,22 +67,116 @@ unsafe impl Sync for Connection {}
 
 #[async_trait]
 pub trait ConnectionStorage: Send + Sync {
-    async fn insert_message(&mut self, message: Message);
-    async fn insert_message_for(&mut self, message: Message, did_to: String);
-    async fn get_next(&mut self, did: String) -> Option<Message>;
-    async fn get_messages(&mut self, did: String, batch_size: usize) -> Option<Vec<Message>>;
+    async fn insert_message(&self, message: Message);
+    async fn insert_message_for(&self, message: Message, did_to: String);
+    /// Puts messages taken from the queue back in front of it, in order.
+    async fn requeue(&self, messages: Vec<Message>, did_to: String);
+    async fn get_next(&self, did: String) -> Option<Message>;
+    async fn get_messages(&self, did: String, batch_size: usize) -> Option<Vec<Message>>;
     async fn get(&self, did: String) -> Option<Connection>;
+    async fn list(&self) -> Vec<(String, usize)>;
+    async fn purge(&self, did: String) -> usize;
+    async fn remove(&self, did: String) -> Option<Connection>;
+    async fn set_device(&self, did: String, device: Option<DeviceInfo>);
+    async fn get_device(&self, did: String) -> Option<DeviceInfo>;
+    /// Moves the connection of `from` to `to`, merging queues if both exist.
+    async fn migrate(&self, from: String, to: String) -> bool;
 }
 
-#[derive(Debug, Default, PartialEq, Deserialize)]
+const SHARDS: usize = 32;
+
 pub struct Connections {
-    pub connections: HashMap<String, Connection>,
+    shards: Vec<Mutex<HashMap<String, Connection>>>,
+    /// Messages queued across all connections, reported as `queue_depth`.
+    queued: AtomicUsize,
+    instrumentation: Arc<dyn Instrumentation>,
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Connections {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            queued: AtomicUsize::new(0),
+            instrumentation: Arc::new(NoInstrumentation::default()),
+        }
+    }
+}
+
+impl fmt::Debug for Connections {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connections")
+            .field("shards", &self.shards)
+            .finish()
+    }
 }
 
 impl Connections {
     pub fn new() -> Connections {
         Connections::default()
     }
+
+    pub fn instrumentation(mut self, instrumentation: Arc<dyn Instrumentation>) -> Self {
+        self.instrumentation = instrumentation;
+        self
+    }
+
+    /// Snapshot of every connection with its queued messages, sorted by did.
+    pub async fn export(&self) -> Vec<Connection> {
+        let mut exported = Vec::new();
+        for shard in &self.shards {
+            let mut connections = shard.lock().await;
+            for connection in connections.values_mut() {
+                let before = connection.messages.len();
+                connection.remove_expired();
+                self.resized(before, connection.messages.len());
+                exported.push(connection.clone());
+            }
+        }
+        exported.sort_by(|a, b| a.did.cmp(&b.did));
+        exported
+    }
+
+    /// Merges exported connections, appending their messages to existing queues.
+    pub async fn import(&self, imported: Vec<Connection>) {
+        for mut connection in imported {
+            connection.remove_expired();
+            self.resized(0, connection.messages.len());
+            let mut connections = self.shard(&connection.did).lock().await;
+            match connections.get_mut(&connection.did) {
+                Some(existing) => {
+                    existing.messages.append(&mut connection.messages);
+                    if connection.device.is_some() {
+                        existing.device = connection.device;
+                    }
+                }
+                None => {
+                    connections.insert(connection.did.to_string(), connection);
+                }
+            }
+        }
+    }
+
+    fn resized(&self, before: usize, after: usize) {
+        if before == after {
+            return;
+        }
+        let queued = if after > before {
+            self.queued.fetch_add(after - before, Ordering::SeqCst) + after - before
+        } else {
+            self.queued.fetch_sub(before - after, Ordering::SeqCst) - (before - after)
+        };
+        self.instrumentation.queue_depth(queued);
+    }
+
+    fn shard(&self, did: &str) -> &Mutex<HashMap<String, Connection>> {
+        &self.shards[self.shard_index(did)]
+    }
+
+    fn shard_index(&self, did: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        did.hash(&mut hasher);
+        hasher.finish() as usize % self.shards.len()
+    }
 }
 
 unsafe impl Send for Connections {}
@@ -61,50 +184,70 @@ unsafe impl Sync for Connections {}
 
 #[async_trait]
 impl ConnectionStorage for Connections {
-    async fn insert_message(&mut self, message: Message) {
+    async fn insert_message(&self, message: Message) {
         let dids = message.get_didcomm_header().to.to_vec();
-        for did in &dids {
-            match self.connections.get_mut(did) {
-                Some(connection) => {
-                    connection.messages.push_back(message.clone());
-                }
-                None => {
-                    let mut connection = Connection::new(did.to_string(), Default::default());
-                    connection.messages.push_back(message.clone());
-                    self.connections.insert(did.to_string(), connection);
-                }
-            }
+        for did in dids {
+            self.insert_message_for(message.clone(), did).await;
         }
     }
 
-    async fn insert_message_for(&mut self, message: Message, did_to: String) {
-        match self.connections.get_mut(&did_to) {
-            Some(connection) => {
-                connection.messages.push_back(message);
-            }
-            None => {
-                let mut connection = Connection::new(did_to.to_string(), Default::default());
-                connection.messages.push_back(message);
-                self.connections.insert(did_to.to_string(), connection);
-            }
+    async fn insert_message_for(&self, message: Message, did_to: String) {
+        let mut connections = self.shard(&did_to).lock().await;
+        let messages = &mut connections
+            .entry(did_to.to_string())
+            .or_insert_with(|| Connection::new(did_to.to_string(), Default::default()))
+            .messages;
+        messages.push_back(message);
+        debug!(did = %did_to, depth = messages.len(), "queued message");
+        self.resized(0, 1);
+    }
+
+    async fn requeue(&self, messages: Vec<Message>, did_to: String) {
+        let mut connections = self.shard(&did_to).lock().await;
+        let queue = &mut connections
+            .entry(did_to.to_string())
+            .or_insert_with(|| Connection::new(did_to.to_string(), Default::default()))
+            .messages;
+        let requeued = messages.len();
+        for message in messages.into_iter().rev() {
+            queue.push_front(message);
         }
+        debug!(did = %did_to, depth = queue.len(), "requeued messages");
+        self.resized(0, requeued);
     }
 
-    async fn get_next(&mut self, did: String) -> Option<Message> {
-        match self.connections.get_mut(&did) {
-            Some(connection) => connection.messages.pop_front(),
+    async fn get_next(&self, did: String) -> Option<Message> {
+        let mut connections = self.shard(&did).lock().await;
+        match connections.get_mut(&did) {
+            Some(connection) => {
+                let before = connection.messages.len();
+                connection.remove_expired();
+                let message = connection.messages.pop_front();
+                debug!(%did, served = message.is_some() as usize, depth = connection.messages.len(), "picked up messages");
+                if message.is_some() {
+                    self.instrumentation.pickups_served(1);
+                }
+                self.resized(before, connection.messages.len());
+                message
+            }
             None => None,
         }
     }
 
-    async fn get_messages(&mut self, did: String, batch_size: usize) -> Option<Vec<Message>> {
-        match self.connections.get_mut(&did) {
+    async fn get_messages(&self, did: String, batch_size: usize) -> Option<Vec<Message>> {
+        let mut connections = self.shard(&did).lock().await;
+        match connections.get_mut(&did) {
             Some(connection) => {
+                let before = connection.messages.len();
+                connection.remove_expired();
                 let messages = connection
                     .messages
                     .drain(0..batch_size.min(connection.messages.len()));
 
                 let messages: Vec<Message> = messages.collect();
+                debug!(%did, served = messages.len(), depth = connection.messages.len(), "picked up messages");
+                self.instrumentation.pickups_served(messages.len());
+                self.resized(before, connection.messages.len());
                 Some(messages)
             }
             None => None,
@@ -112,27 +255,378 @@ impl ConnectionStorage for Connections {
     }
 
     async fn get(&self, did: String) -> Option<Connection> {
-        self.connections.get(&did).cloned()
+        let connections = self.shard(&did).lock().await;
+        connections.get(&did).cloned().map(|mut connection| {
+            connection.remove_expired();
+            connection
+        })
+    }
+
+    async fn list(&self) -> Vec<(String, usize)> {
+        let mut dids = Vec::new();
+        for shard in &self.shards {
+            let mut connections = shard.lock().await;
+            for (did, connection) in connections.iter_mut() {
+                let before = connection.messages.len();
+                connection.remove_expired();
+                self.resized(before, connection.messages.len());
+                dids.push((did.to_string(), connection.messages.len()));
+            }
+        }
+        dids.sort();
+        dids
+    }
+
+    async fn purge(&self, did: String) -> usize {
+        let mut connections = self.shard(&did).lock().await;
+        let purged = match connections.get_mut(&did) {
+            Some(connection) => connection.messages.drain(..).count(),
+            None => 0,
+        };
+        self.resized(purged, 0);
+        debug!(%did, purged, "purged messages");
+        purged
+    }
+
+    async fn remove(&self, did: String) -> Option<Connection> {
+        let mut connections = self.shard(&did).lock().await;
+        debug!(%did, "removed connection");
+        let removed = connections.remove(&did);
+        if let Some(connection) = &removed {
+            self.resized(connection.messages.len(), 0);
+        }
+        removed
+    }
+
+    async fn set_device(&self, did: String, device: Option<DeviceInfo>) {
+        let mut connections = self.shard(&did).lock().await;
+        debug!(%did, registered = device.is_some(), "set device");
+        connections
+            .entry(did.to_string())
+            .or_insert_with(|| Connection::new(did, Default::default()))
+            .device = device;
+    }
+
+    async fn get_device(&self, did: String) -> Option<DeviceInfo> {
+        let connections = self.shard(&did).lock().await;
+        connections
+            .get(&did)
+            .and_then(|connection| connection.device.clone())
+    }
+
+    /// Holds both shards, locked in index order, so no insert or pickup
+    /// sees the connection in neither place. The queue of `from` goes
+    /// behind the one `to` already has.
+    async fn migrate(&self, from: String, to: String) -> bool {
+        let (from_index, to_index) = (self.shard_index(&from), self.shard_index(&to));
+        let mut first = self.shards[from_index.min(to_index)].lock().await;
+        let mut second = if from_index == to_index {
+            None
+        } else {
+            Some(self.shards[from_index.max(to_index)].lock().await)
+        };
+        let from_shard = match second.as_deref_mut() {
+            Some(second) if from_index > to_index => second,
+            _ => &mut *first,
+        };
+        let mut connection = match from_shard.remove(&from) {
+            Some(connection) => connection,
+            None => return false,
+        };
+        debug!(%from, %to, depth = connection.messages.len(), "migrated connection");
+        let to_shard = match second.as_deref_mut() {
+            Some(second) if to_index > from_index => second,
+            _ => &mut *first,
+        };
+        match to_shard.get_mut(&to) {
+            Some(existing) => {
+                existing.messages.append(&mut connection.messages);
+                if connection.device.is_some() {
+                    existing.device = connection.device.take();
+                }
+            }
+            None => {
+                connection.did = to.to_string();
+                to_shard.insert(to, connection);
+            }
+        }
+        true
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Arc;
 
     #[tokio::test]
     async fn test_insert_message() {
-        let mut connections = Connections::default();
+        let connections = Connections::default();
         let message = Message::new().to(&["did:test"]);
         connections.insert_message(message).await;
 
-        assert_eq!(connections.connections.len(), 1);
+        let connection = connections.get("did:test".to_string()).await.unwrap();
+        assert_eq!(connection.messages.len(), 1);
 
         let message = Message::new().to(&["did:test"]);
         connections.insert_message(message).await;
 
-        assert_eq!(connections.connections.len(), 1);
-        let connection = connections.connections.get("did:test").unwrap();
+        let connection = connections.get("did:test".to_string()).await.unwrap();
         assert_eq!(connection.messages.len(), 2);
+        assert!(connections.get("did:other".to_string()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_export_import() {
+        let connections = Connections::default();
+        connections
+            .insert_message(Message::new().to(&["did:a", "did:b"]))
+            .await;
+        let exported = connections.export().await;
+        assert_eq!(exported.len(), 2);
+        assert_eq!(exported[0].did, "did:a");
+
+        let exported: Vec<Connection> =
+            serde_json::from_str(&serde_json::to_string(&exported).unwrap()).unwrap();
+        connections.import(exported.clone()).await;
+        let imported = Connections::default();
+        imported.import(exported).await;
+        assert_eq!(
+            connections.list().await,
+            vec![("did:a".to_string(), 2), ("did:b".to_string(), 2)]
+        );
+        assert_eq!(
+            imported.list().await,
+            vec![("did:a".to_string(), 1), ("did:b".to_string(), 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_migrate() {
+        let connections = Connections::default();
+        connections
+            .insert_message(Message::new().to(&["did:old", "did:new"]))
+            .await;
+        let device = DeviceInfo {
+            device_token: "token".to_string(),
+            device_platform: "fcm".to_string(),
+        };
+        connections
+            .set_device("did:old".to_string(), Some(device.clone()))
+            .await;
+
+        assert!(
+            connections
+                .migrate("did:old".to_string(), "did:new".to_string())
+                .await
+        );
+        assert!(connections.get("did:old".to_string()).await.is_none());
+        let connection = connections.get("did:new".to_string()).await.unwrap();
+        assert_eq!(connection.messages.len(), 2);
+        assert_eq!(connection.device, Some(device));
+        assert!(
+            !connections
+                .migrate("did:old".to_string(), "did:new".to_string())
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_migrate_merges_queues() {
+        let connections = Connections::default();
+        let from = "did:old".to_string();
+        let same_shard = (0..)
+            .map(|i| format!("did:new:{}", i))
+            .find(|did| connections.shard_index(did) == connections.shard_index(&from))
+            .unwrap();
+        let other_shard = (0..)
+            .map(|i| format!("did:new:{}", i))
+            .find(|did| connections.shard_index(did) != connections.shard_index(&from))
+            .unwrap();
+        for to in [same_shard, other_shard] {
+            connections
+                .insert_message_for(Message::new().thid("old"), from.to_string())
+                .await;
+            connections
+                .insert_message_for(Message::new().thid("new"), to.to_string())
+                .await;
+            assert!(connections.migrate(from.to_string(), to.to_string()).await);
+            assert!(connections.get(from.to_string()).await.is_none());
+            let connection = connections.get(to.to_string()).await.unwrap();
+            assert_eq!(connection.did, to);
+            let thids: Vec<Option<String>> = connection
+                .messages
+                .iter()
+                .map(|message| message.get_didcomm_header().thid.clone())
+                .collect();
+            assert_eq!(
+                thids,
+                vec![Some("new".to_string()), Some("old".to_string())]
+            );
+        }
+        assert_eq!(connections.queued.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_requeue() {
+        let connections = Connections::default();
+        for id in ["1", "2", "3"] {
+            connections
+                .insert_message_for(Message::new().thid(id), "did:test".to_string())
+                .await;
+        }
+        let taken = connections
+            .get_messages("did:test".to_string(), 2)
+            .await
+            .unwrap();
+        connections.requeue(taken, "did:test".to_string()).await;
+        let thids: Vec<Option<String>> = connections
+            .get_messages("did:test".to_string(), 3)
+            .await
+            .unwrap()
+            .iter()
+            .map(|message| message.get_didcomm_header().thid.clone())
+            .collect();
+        assert_eq!(
+            thids,
+            vec![
+                Some("1".to_string()),
+                Some("2".to_string()),
+                Some("3".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_access() {
+        let connections: Arc<dyn ConnectionStorage> = Arc::new(Connections::default());
+        let mut tasks = Vec::new();
+        for i in 0..100 {
+            let connections = connections.clone();
+            tasks.push(tokio::spawn(async move {
+                let did = format!("did:test:{}", i % 10);
+                connections
+                    .insert_message_for(Message::new(), did.to_string())
+                    .await;
+                connections.get_messages(did, 1).await
+            }));
+        }
+        let mut received = 0;
+        for task in tasks {
+            received += task.await.unwrap().unwrap().len();
+        }
+        for i in 0..10 {
+            let did = format!("did:test:{}", i);
+            received += connections.get_messages(did, 100).await.unwrap().len();
+        }
+        assert_eq!(received, 100);
+    }
+
+    #[tokio::test]
+    async fn test_list_purge_remove() {
+        let connections = Connections::default();
+        connections
+            .insert_message_for(Message::new(), "did:test:a".to_string())
+            .await;
+        connections
+            .insert_message_for(Message::new(), "did:test:a".to_string())
+            .await;
+        connections
+            .insert_message_for(Message::new(), "did:test:b".to_string())
+            .await;
+        assert_eq!(
+            connections.list().await,
+            vec![("did:test:a".to_string(), 2), ("did:test:b".to_string(), 1)]
+        );
+
+        assert_eq!(connections.purge("did:test:a".to_string()).await, 2);
+        assert_eq!(connections.purge("did:test:c".to_string()).await, 0);
+        assert_eq!(
+            connections.list().await,
+            vec![("did:test:a".to_string(), 0), ("did:test:b".to_string(), 1)]
+        );
+
+        assert!(connections.remove("did:test:b".to_string()).await.is_some());
+        assert!(connections.remove("did:test:b".to_string()).await.is_none());
+        assert_eq!(connections.list().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_instrumentation() {
+        use crate::instrumentation::tests::CountingInstrumentation;
+
+        let instrumentation = Arc::new(CountingInstrumentation::default());
+        let connections = Connections::new().instrumentation(instrumentation.clone());
+        for _ in 0..3 {
+            connections
+                .insert_message_for(Message::new(), "did:test".to_string())
+                .await;
+        }
+        connections.get_next("did:test".to_string()).await;
+        assert_eq!(instrumentation.get("queue_depth"), 2);
+        connections.get_messages("did:test".to_string(), 10).await;
+        connections.get_next("did:test".to_string()).await;
+        assert_eq!(instrumentation.get("pickups_served"), 3);
+        assert_eq!(instrumentation.get("queue_depth"), 0);
+
+        connections
+            .requeue(vec![Message::new()], "did:test".to_string())
+            .await;
+        connections
+            .insert_message_for(Message::new(), "did:other".to_string())
+            .await;
+        assert_eq!(instrumentation.get("queue_depth"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_device() {
+        let connections = Connections::default();
+        assert!(connections
+            .get_device("did:test".to_string())
+            .await
+            .is_none());
+        let device = DeviceInfo {
+            device_token: "token".to_string(),
+            device_platform: "android".to_string(),
+        };
+        connections
+            .set_device("did:test".to_string(), Some(device.clone()))
+            .await;
+        assert_eq!(
+            connections.get_device("did:test".to_string()).await,
+            Some(device)
+        );
+        connections.set_device("did:test".to_string(), None).await;
+        assert!(connections
+            .get_device("did:test".to_string())
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_expired_messages() {
+        let connections = Connections::default();
+        let expired: Message = serde_json::from_value({
+            let mut value = serde_json::to_value(Message::new()).unwrap();
+            value["expires_time"] = serde_json::json!(1);
+            value
+        })
+        .unwrap();
+        connections
+            .insert_message_for(expired, "did:test".to_string())
+            .await;
+        connections
+            .insert_message_for(Message::new(), "did:test".to_string())
+            .await;
+
+        let connection = connections.get("did:test".to_string()).await.unwrap();
+        assert_eq!(connection.messages.len(), 1);
+
+        let messages = connections
+            .get_messages("did:test".to_string(), 10)
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(!is_expired(&messages[0]));
     }
 }
@@ -0,0 +1,143 @@
+// https://fetch.spec.whatwg.org/#http-cors-protocol
+use crate::config::{CorsConfig, CorsPolicy};
+
+impl CorsConfig {
+    /// The policy of the longest route prefix of `path`, matched on whole
+    /// segments: `/admin` covers `/admin/connections` but not `/administrator`.
+    pub fn policy(&self, path: &str) -> &CorsPolicy {
+        self.routes
+            .iter()
+            .filter(|(prefix, _)| Self::covers(prefix.trim_end_matches('/'), path))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, policy)| policy)
+            .unwrap_or(&self.policy)
+    }
+
+    fn covers(prefix: &str, path: &str) -> bool {
+        match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+impl CorsPolicy {
+    /// The `Access-Control-Allow-Origin` value for `origin`, if it is allowed.
+    /// A wildcard never gets credentials, matching origins are echoed back.
+    pub fn allow_origin(&self, origin: Option<&str>) -> Option<String> {
+        let origin = origin?;
+        if self
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+        {
+            Some(origin.to_string())
+        } else if self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            Some("*".to_string())
+        } else {
+            None
+        }
+    }
+
+    pub fn headers(&self, origin: Option<&str>, preflight: bool) -> Vec<(&'static str, String)> {
+        let allow_origin = match self.allow_origin(origin) {
+            Some(allow_origin) => allow_origin,
+            None => return vec![],
+        };
+        let mut headers = vec![];
+        if allow_origin != "*" {
+            headers.push(("Vary", "Origin".to_string()));
+            if self.allow_credentials {
+                headers.push(("Access-Control-Allow-Credentials", "true".to_string()));
+            }
+        }
+        headers.push(("Access-Control-Allow-Origin", allow_origin));
+        if preflight {
+            headers.push((
+                "Access-Control-Allow-Methods",
+                self.allowed_methods.join(", "),
+            ));
+            headers.push((
+                "Access-Control-Allow-Headers",
+                self.allowed_headers.join(", "),
+            ));
+            if let Some(max_age) = self.max_age {
+                headers.push(("Access-Control-Max-Age", max_age.to_string()));
+            }
+        }
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header<'a>(headers: &'a [(&'static str, String)], name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn test_wildcard_without_credentials() {
+        let policy = CorsPolicy {
+            allow_credentials: true,
+            ..Default::default()
+        };
+        let headers = policy.headers(Some("https://wallet.example"), true);
+        assert_eq!(header(&headers, "Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(header(&headers, "Access-Control-Allow-Credentials"), None);
+        assert_eq!(
+            header(&headers, "Access-Control-Allow-Methods"),
+            Some("GET, POST, OPTIONS")
+        );
+        assert_eq!(header(&headers, "Access-Control-Max-Age"), Some("600"));
+        assert!(policy.headers(None, false).is_empty());
+    }
+
+    #[test]
+    fn test_route_policy() {
+        let mut config = CorsConfig::default();
+        config.routes.insert(
+            "/admin".to_string(),
+            CorsPolicy {
+                allowed_origins: vec!["https://admin.example".to_string()],
+                allow_credentials: true,
+                ..Default::default()
+            },
+        );
+        config.routes.insert(
+            "/admin/connections".to_string(),
+            CorsPolicy {
+                allowed_origins: vec![],
+                ..Default::default()
+            },
+        );
+
+        let admin = config.policy("/admin/invitation/rotate");
+        let headers = admin.headers(Some("https://admin.example"), false);
+        assert_eq!(
+            header(&headers, "Access-Control-Allow-Origin"),
+            Some("https://admin.example")
+        );
+        assert_eq!(
+            header(&headers, "Access-Control-Allow-Credentials"),
+            Some("true")
+        );
+        assert_eq!(header(&headers, "Vary"), Some("Origin"));
+        assert!(admin
+            .headers(Some("https://evil.example"), false)
+            .is_empty());
+
+        let connections = config.policy("/admin/connections");
+        assert!(connections
+            .headers(Some("https://admin.example"), false)
+            .is_empty());
+        assert_eq!(config.policy("/didcomm"), &config.policy);
+        assert_eq!(config.policy("/administrator"), &config.policy);
+        assert_eq!(config.policy("/admin/connectionsx"), admin);
+        assert_eq!(config.policy("/admin"), admin);
+    }
+}
@@ -8,6 +8,7 @@ use serde_json::Value;
 pub struct DidDocBuilder {
     did: Option<String>,
     keypair: Option<KeyPair>,
+    authentication: Option<KeyPair>,
     endpoint: Option<String>,
     #[cfg(feature = "iota")]
     iota_document: Option<IotaDocument>,
@@ -18,6 +19,7 @@ impl DidDocBuilder {
         DidDocBuilder {
             did: None,
             keypair: None,
+            authentication: None,
             endpoint: None,
             #[cfg(feature = "iota")]
             iota_document: None,
@@ -34,6 +36,12 @@ impl DidDocBuilder {
         self
     }
 
+    /// Ed25519 key the subject signs with, such as its `from_prior`s.
+    pub fn authentication(&mut self, keypair: KeyPair) -> &mut Self {
+        self.authentication = Some(keypair);
+        self
+    }
+
     pub fn endpoint(&mut self, endpoint: String) -> &mut Self {
         self.endpoint = Some(endpoint);
         self
@@ -55,6 +63,18 @@ impl DidDocBuilder {
         let did_key = &did_doc.id;
         let mut did_doc = serde_json::to_value(&did_doc).unwrap();
         did_doc["id"] = serde_json::to_value(self.did.as_ref().unwrap()).unwrap();
+        if let Some(keypair) = &self.authentication {
+            let mut method = keypair
+                .get_did_document(CONFIG_LD_PUBLIC)
+                .verification_method
+                .remove(0);
+            method.private_key = None;
+            did_doc["authentication"] = serde_json::json!([method.id]);
+            did_doc["verificationMethod"]
+                .as_array_mut()
+                .unwrap()
+                .push(serde_json::to_value(method).unwrap());
+        }
         match &self.endpoint {
             Some(endpoint) => {
                 did_doc["service"] = serde_json::json!([
@@ -116,7 +136,7 @@ mod tests {
     use super::*;
     use crate::config::Config;
     use base58::FromBase58;
-    use did_key::{generate, X25519KeyPair};
+    use did_key::{generate, Ed25519KeyPair, X25519KeyPair};
     use rocket;
 
     #[test]
@@ -165,6 +185,25 @@ mod tests {
         assert!(did_doc.get("service").is_some());
     }
 
+    #[test]
+    fn test_build_diddoc_with_authentication() {
+        let signing = generate::<Ed25519KeyPair>(None);
+        let kid = crate::keystore::key_id(&signing.get_did_document(Default::default()).id);
+        let did_doc = DidDocBuilder::new()
+            .did("did:web:example.com".to_string())
+            .keypair(generate::<X25519KeyPair>(None))
+            .authentication(signing)
+            .build()
+            .unwrap();
+
+        assert_eq!(did_doc["authentication"], serde_json::json!([kid]));
+        assert!(did_doc["verificationMethod"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|method| method["id"] == kid));
+    }
+
     #[cfg(feature = "iota")]
     #[tokio::test]
     async fn test_build_iota_diddoc() {
@@ -0,0 +1,58 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum MediatorEvent {
+    MessageQueued { did: String, message_id: String },
+    MediationGranted { did: String },
+    ConnectionCompleted { did: String },
+    BasicMessageReceived { did: String, message_id: String },
+}
+
+impl MediatorEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MediatorEvent::MessageQueued { .. } => "message_queued",
+            MediatorEvent::MediationGranted { .. } => "mediation_granted",
+            MediatorEvent::ConnectionCompleted { .. } => "connection_completed",
+            MediatorEvent::BasicMessageReceived { .. } => "basic_message_received",
+        }
+    }
+
+    pub fn did(&self) -> &str {
+        match self {
+            MediatorEvent::MessageQueued { did, .. } => did,
+            MediatorEvent::MediationGranted { did } => did,
+            MediatorEvent::ConnectionCompleted { did } => did,
+            MediatorEvent::BasicMessageReceived { did, .. } => did,
+        }
+    }
+}
+
+#[async_trait]
+pub trait EventListener: Send + Sync {
+    async fn notify(&self, event: &MediatorEvent);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize() {
+        let event = MediatorEvent::MessageQueued {
+            did: "did:key:test".to_string(),
+            message_id: "42".to_string(),
+        };
+        assert_eq!(event.name(), "message_queued");
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "event": "message_queued",
+                "did": "did:key:test",
+                "message_id": "42"
+            })
+        );
+    }
+}
@@ -0,0 +1,187 @@
+// https://identity.foundation/didcomm-messaging/spec/#did-rotation
+use crate::keystore::{key_id, KeyStore, ED25519_PREFIX};
+use chrono::Utc;
+use did_key::KeyMaterial;
+use didcomm_rs::Message;
+use ed25519_dalek::{PublicKey, Signature};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::convert::TryFrom;
+use std::error::Error;
+
+/// Seconds a `from_prior` stays valid after its `iat`.
+pub const MAX_AGE: i64 = 24 * 60 * 60;
+
+/// Claims of a `from_prior` JWT: `iss` rotated to `sub`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FromPrior {
+    pub sub: String,
+    pub iss: String,
+    pub iat: i64,
+}
+
+fn encode(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(data: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(base64::decode_config(data, base64::URL_SAFE_NO_PAD)?)
+}
+
+/// X25519 keys cannot sign, so only Ed25519 did:keys issue `from_prior`s.
+fn verifying_key(did: &str) -> Result<PublicKey, Box<dyn Error>> {
+    if !did.starts_with(ED25519_PREFIX) {
+        return Err("from_prior needs an ed25519 did:key".into());
+    }
+    let public = did_key::resolve(did)
+        .map_err(|_| "could not resolve prior did")?
+        .public_key_bytes();
+    Ok(PublicKey::from_bytes(&public)?)
+}
+
+impl FromPrior {
+    pub fn new(prior: &str, did: &str) -> Self {
+        FromPrior {
+            sub: did.to_string(),
+            iss: prior.to_string(),
+            iat: Utc::now().timestamp(),
+        }
+    }
+
+    /// Compact JWT signed by the key of the `iss` did in `keys`.
+    pub async fn sign(&self, keys: &dyn KeyStore) -> Result<String, Box<dyn Error>> {
+        let kid = key_id(&self.iss);
+        let header = json!({"alg": "EdDSA", "typ": "JWT", "kid": kid});
+        let signing_input = format!(
+            "{}.{}",
+            encode(header.to_string().as_bytes()),
+            encode(&serde_json::to_vec(self)?)
+        );
+        let signature = keys.sign(&kid, signing_input.as_bytes()).await?;
+        Ok(format!("{}.{}", signing_input, encode(&signature)))
+    }
+
+    /// Verifies a JWT signed by the key of its `iss`.
+    pub fn verify(jwt: &str) -> Result<Self, Box<dyn Error>> {
+        let mut parts = jwt.split('.');
+        let (header, claims, signature) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(header), Some(claims), Some(signature)) if parts.next().is_none() => {
+                (header, claims, signature)
+            }
+            _ => return Err("from_prior is not a compact JWT".into()),
+        };
+        let decoded: serde_json::Value = serde_json::from_slice(&decode(header)?)?;
+        let prior: FromPrior = serde_json::from_slice(&decode(claims)?)?;
+        if decoded["alg"] != "EdDSA" {
+            return Err("unsupported from_prior alg".into());
+        }
+        match decoded["kid"].as_str() {
+            Some(kid) if kid.split('#').next() == Some(prior.iss.as_str()) => {}
+            _ => return Err("from_prior kid does not belong to iss".into()),
+        }
+        if prior.iss == prior.sub {
+            return Err("from_prior rotates to the same did".into());
+        }
+        let now = Utc::now().timestamp();
+        if prior.iat > now + 60 {
+            return Err("from_prior issued in the future".into());
+        }
+        if prior.iat < now - MAX_AGE {
+            return Err("from_prior expired".into());
+        }
+        let signature = Signature::try_from(decode(signature)?.as_slice())?;
+        verifying_key(&prior.iss)?
+            .verify_strict(format!("{}.{}", header, claims).as_bytes(), &signature)
+            .map_err(|_| "invalid from_prior signature")?;
+        Ok(prior)
+    }
+
+    pub fn from_message(message: &Message) -> Option<String> {
+        message
+            .get_application_params()
+            .find(|(key, _)| *key == "from_prior")
+            .map(|(_, jwt)| jwt.to_string())
+    }
+
+    pub fn apply(jwt: String, message: Message) -> Message {
+        message.add_header_field("from_prior".to_string(), jwt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use did_key::{generate, DIDCore, Ed25519KeyPair, X25519KeyPair};
+
+    #[tokio::test]
+    async fn test_ed25519_from_prior() {
+        let prior = generate::<Ed25519KeyPair>(None);
+        let prior_did = prior.get_did_document(Default::default()).id;
+        let jwt = FromPrior::new(&prior_did, "did:key:new")
+            .sign(&prior)
+            .await
+            .unwrap();
+        let verified = FromPrior::verify(&jwt).unwrap();
+        assert_eq!(verified.iss, prior_did);
+        assert_eq!(verified.sub, "did:key:new");
+        assert!(FromPrior::verify("a.b").is_err());
+
+        let other = generate::<Ed25519KeyPair>(None);
+        let other_did = other.get_did_document(Default::default()).id;
+        assert!(FromPrior::new(&other_did, "did:key:new")
+            .sign(&prior)
+            .await
+            .is_err());
+
+        let mut parts: Vec<&str> = jwt.split('.').collect();
+        let forged =
+            encode(&serde_json::to_vec(&FromPrior::new(&prior_did, "did:key:evil")).unwrap());
+        parts[1] = &forged;
+        assert!(FromPrior::verify(&parts.join(".")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_x25519_from_prior() {
+        let prior = generate::<X25519KeyPair>(None);
+        let prior_did = prior.get_did_document(Default::default()).id;
+        assert!(FromPrior::new(&prior_did, "did:key:new")
+            .sign(&prior)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_iat() {
+        let prior = generate::<Ed25519KeyPair>(None);
+        let prior_did = prior.get_did_document(Default::default()).id;
+        let mut stale = FromPrior::new(&prior_did, "did:key:new");
+        stale.iat -= MAX_AGE + 1;
+        assert!(FromPrior::verify(&stale.sign(&prior).await.unwrap()).is_err());
+
+        let jwt = FromPrior::new(&prior_did, "did:key:new")
+            .sign(&prior)
+            .await
+            .unwrap();
+        let mut parts: Vec<&str> = jwt.split('.').collect();
+        let claims = encode(
+            json!({"iss": prior_did, "sub": "did:key:new"})
+                .to_string()
+                .as_bytes(),
+        );
+        parts[1] = &claims;
+        let signing_input = format!("{}.{}", parts[0], parts[1]);
+        let signature = KeyStore::sign(&prior, &key_id(&prior_did), signing_input.as_bytes())
+            .await
+            .unwrap();
+        let signature = encode(&signature);
+        parts[2] = &signature;
+        assert!(FromPrior::verify(&parts.join(".")).is_err());
+    }
+
+    #[test]
+    fn test_message_header() {
+        let message = FromPrior::apply("jwt".to_string(), Message::new());
+        assert_eq!(FromPrior::from_message(&message), Some("jwt".to_string()));
+        assert_eq!(FromPrior::from_message(&Message::new()), None);
+    }
+}
@@ -1,7 +1,6 @@
 use crate::connections::ConnectionStorage;
-use async_mutex::Mutex;
+use crate::keystore::KeyStore;
 use async_trait::async_trait;
-use did_key::KeyPair;
 use didcomm_rs::Message;
 use serde_json::Value;
 use std::error::Error;
@@ -12,8 +11,25 @@ pub enum HandlerResponse {
     Skipped,
     Processed,
     Send(String, Box<Message>),
-    Forward(Vec<String>, Value),
+    /// Answer to the sender, sealed by the mediator whatever the return route.
+    Reply(String, Box<Message>),
+    Forward(Vec<String>, Box<Message>),
     Response(Value),
+    LiveDelivery(String, bool),
+}
+
+impl HandlerResponse {
+    pub fn outcome(&self) -> &'static str {
+        match self {
+            HandlerResponse::Skipped => "skipped",
+            HandlerResponse::Processed => "processed",
+            HandlerResponse::Send(_, _) => "send",
+            HandlerResponse::Reply(_, _) => "reply",
+            HandlerResponse::Forward(_, _) => "forward",
+            HandlerResponse::Response(_) => "response",
+            HandlerResponse::LiveDelivery(_, _) => "live-delivery",
+        }
+    }
 }
 
 unsafe impl Send for HandlerResponse {}
@@ -24,7 +40,7 @@ pub trait DidcommHandler: Send + Sync {
     async fn handle(
         &self,
         request: &Message,
-        key: Option<&KeyPair>,
-        connections: Option<&Arc<Mutex<Box<dyn ConnectionStorage>>>>,
+        keys: Option<&dyn KeyStore>,
+        connections: Option<&Arc<dyn ConnectionStorage>>,
     ) -> Result<HandlerResponse, Box<dyn Error>>;
 }
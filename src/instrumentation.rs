@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+pub trait Instrumentation: Send + Sync {
+    fn inbound(&self, _protocol: &str) {}
+
+    fn decrypt_failure(&self) {}
+
+    fn forward_queued(&self) {}
+
+    fn pickups_served(&self, _count: usize) {}
+
+    /// Messages queued across all connections.
+    fn queue_depth(&self, _depth: usize) {}
+
+    fn resolved(&self, _duration: Duration, _cache_hit: bool) {}
+
+    fn delivery_attempt(&self, _transport: &str, _delivered: bool) {}
+}
+
+#[derive(Default)]
+pub struct NoInstrumentation {}
+
+impl Instrumentation for NoInstrumentation {}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    pub struct CountingInstrumentation {
+        pub counts: Mutex<HashMap<String, usize>>,
+    }
+
+    impl CountingInstrumentation {
+        fn count(&self, key: &str, count: usize) {
+            *self
+                .counts
+                .lock()
+                .unwrap()
+                .entry(key.to_string())
+                .or_default() += count;
+        }
+
+        pub fn get(&self, key: &str) -> usize {
+            self.counts
+                .lock()
+                .unwrap()
+                .get(key)
+                .cloned()
+                .unwrap_or_default()
+        }
+    }
+
+    impl Instrumentation for CountingInstrumentation {
+        fn inbound(&self, protocol: &str) {
+            self.count(&format!("inbound {}", protocol), 1);
+        }
+
+        fn decrypt_failure(&self) {
+            self.count("decrypt_failure", 1);
+        }
+
+        fn forward_queued(&self) {
+            self.count("forward_queued", 1);
+        }
+
+        fn pickups_served(&self, count: usize) {
+            self.count("pickups_served", count);
+        }
+
+        fn queue_depth(&self, depth: usize) {
+            self.counts
+                .lock()
+                .unwrap()
+                .insert("queue_depth".to_string(), depth);
+        }
+
+        fn resolved(&self, _duration: Duration, cache_hit: bool) {
+            self.count(if cache_hit { "cache_hit" } else { "cache_miss" }, 1);
+        }
+
+        fn delivery_attempt(&self, transport: &str, _delivered: bool) {
+            self.count(&format!("delivery {}", transport), 1);
+        }
+    }
+
+    #[test]
+    fn test_no_instrumentation() {
+        let instrumentation: Box<dyn Instrumentation> = Box::new(NoInstrumentation::default());
+        instrumentation.inbound("trust-ping/2.0");
+        instrumentation.queue_depth(1);
+    }
+}
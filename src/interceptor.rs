@@ -0,0 +1,22 @@
+use crate::handler::HandlerResponse;
+use async_trait::async_trait;
+use didcomm_rs::Message;
+use std::error::Error;
+
+#[async_trait]
+pub trait Interceptor: Send + Sync {
+    async fn before_handle(
+        &self,
+        _request: &mut Message,
+    ) -> Result<Option<HandlerResponse>, Box<dyn Error>> {
+        Ok(None)
+    }
+
+    async fn after_handle(
+        &self,
+        _request: &Message,
+        response: HandlerResponse,
+    ) -> Result<HandlerResponse, Box<dyn Error>> {
+        Ok(response)
+    }
+}
@@ -0,0 +1,167 @@
+use crate::wallet::RetiredKey;
+use argon2::Argon2;
+use base58::ToBase58;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use did_key::{generate, Ed25519KeyPair, KeyMaterial, X25519KeyPair};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::Path;
+use zeroize::{Zeroize, Zeroizing};
+
+const VERSION: u32 = 1;
+const KDF: &str = "argon2id";
+const CIPHER: &str = "xchacha20poly1305";
+
+/// On-disk form of a [`Keyfile`].
+#[derive(Serialize, Deserialize)]
+struct Encrypted {
+    version: u32,
+    kdf: String,
+    cipher: String,
+    #[serde(with = "hex")]
+    salt: Vec<u8>,
+    #[serde(with = "hex")]
+    nonce: Vec<u8>,
+    #[serde(with = "hex")]
+    ciphertext: Vec<u8>,
+}
+
+/// Base58 encoded private keys of the mediator, encrypted at rest with a
+/// password-derived key: the X25519 key agreement key and the Ed25519 key
+/// signing its `from_prior`s.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Keyfile {
+    pub x25519: String,
+    pub ed25519: String,
+    #[serde(default)]
+    pub retired: Vec<RetiredKey>,
+}
+
+impl Drop for Keyfile {
+    fn drop(&mut self) {
+        self.x25519.zeroize();
+        self.ed25519.zeroize();
+    }
+}
+
+impl Keyfile {
+    pub fn generate() -> Self {
+        Keyfile {
+            x25519: generate::<X25519KeyPair>(None)
+                .private_key_bytes()
+                .to_base58(),
+            ed25519: generate::<Ed25519KeyPair>(None)
+                .private_key_bytes()
+                .to_base58(),
+            retired: Vec::new(),
+        }
+    }
+
+    pub fn from_seed(seed: &str) -> Self {
+        let mut keyfile = Self::generate();
+        keyfile.x25519 = seed.to_string();
+        keyfile
+    }
+
+    fn derive_key(password: &str, salt: &[u8]) -> Result<Zeroizing<[u8; 32]>, Box<dyn Error>> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        Argon2::default()
+            .hash_password_into(password.as_bytes(), salt, &mut key[..])
+            .map_err(|error| error.to_string())?;
+        Ok(key)
+    }
+
+    pub fn encrypt(&self, password: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut salt = vec![0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let key = Self::derive_key(password, &salt)?;
+        let plaintext = Zeroizing::new(serde_json::to_vec(self)?);
+        let ciphertext = XChaCha20Poly1305::new(Key::from_slice(&key[..]))
+            .encrypt(&nonce, plaintext.as_slice())
+            .map_err(|_| "encrypting keyfile failed")?;
+        Ok(serde_json::to_vec_pretty(&Encrypted {
+            version: VERSION,
+            kdf: KDF.to_string(),
+            cipher: CIPHER.to_string(),
+            salt,
+            nonce: nonce.to_vec(),
+            ciphertext,
+        })?)
+    }
+
+    pub fn decrypt(data: &[u8], password: &str) -> Result<Self, Box<dyn Error>> {
+        let encrypted: Encrypted =
+            serde_json::from_slice(data).map_err(|_| "not a mediator keyfile")?;
+        if encrypted.version != VERSION || encrypted.kdf != KDF || encrypted.cipher != CIPHER {
+            return Err("unsupported keyfile format".into());
+        }
+        if encrypted.nonce.len() != 24 {
+            return Err("invalid keyfile nonce".into());
+        }
+        let key = Self::derive_key(password, &encrypted.salt)?;
+        let plaintext = XChaCha20Poly1305::new(Key::from_slice(&key[..]))
+            .decrypt(
+                XNonce::from_slice(&encrypted.nonce),
+                encrypted.ciphertext.as_slice(),
+            )
+            .map(Zeroizing::new)
+            .map_err(|_| "wrong password or corrupted keyfile")?;
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, password: &str) -> Result<Self, Box<dyn Error>> {
+        Self::decrypt(&std::fs::read(path)?, password)
+    }
+
+    /// Writes to a temporary file first so a failed save keeps the old keyfile.
+    pub fn save<P: AsRef<Path>>(&self, path: P, password: &str) -> Result<(), Box<dyn Error>> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, self.encrypt(password)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    pub fn change_password<P: AsRef<Path>>(
+        path: P,
+        password: &str,
+        new_password: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        Self::load(&path, password)?.save(&path, new_password)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let keyfile = Keyfile::generate();
+        let encrypted = keyfile.encrypt("password").unwrap();
+        assert!(!String::from_utf8_lossy(&encrypted).contains(&keyfile.x25519));
+
+        let decrypted = Keyfile::decrypt(&encrypted, "password").unwrap();
+        assert!(decrypted == keyfile);
+        assert!(Keyfile::decrypt(&encrypted, "wrong").is_err());
+        assert!(Keyfile::decrypt(b"stronghold", "password").is_err());
+    }
+
+    #[test]
+    fn test_change_password() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wallet.keys");
+        let keyfile = Keyfile::from_seed("HBTcN2MrXNRj9xF9oi8QqYyuEPv3JLLjQKuEgW9oxVKP");
+        keyfile.save(&path, "old").unwrap();
+
+        Keyfile::change_password(&path, "old", "new").unwrap();
+        assert!(Keyfile::load(&path, "old").is_err());
+        assert!(Keyfile::load(&path, "new").unwrap() == keyfile);
+        assert!(Keyfile::change_password(&path, "old", "other").is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,284 @@
+use crate::message::receive;
+use crate::wallet::Wallet;
+use async_trait::async_trait;
+use did_key::{generate, DIDCore, Ed25519KeyPair, KeyMaterial, KeyPair};
+use didcomm_rs::crypto::{CryptoAlgorithm, SignatureAlgorithm};
+use didcomm_rs::Message;
+use ed25519_dalek::{ExpandedSecretKey, PublicKey, SecretKey};
+use std::convert::TryFrom;
+use std::error::Error;
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+pub const ED25519_PREFIX: &str = "did:key:z6Mk";
+pub const X25519_PREFIX: &str = "did:key:z6LS";
+
+/// Key id of the single key of a did:key.
+pub fn key_id(did: &str) -> String {
+    format!("{}#{}", did, did.trim_start_matches("did:key:"))
+}
+
+/// Private key operations addressed by key id, so the keys can stay in a
+/// store that never hands them out.
+#[async_trait]
+pub trait KeyStore: Send + Sync {
+    /// The DID messages are sent from.
+    fn did(&self) -> String;
+
+    /// Key ids able to decrypt, the current key first.
+    fn key_ids(&self) -> Vec<String>;
+
+    /// X25519 shared secret of the key `kid` and `public_key`.
+    async fn key_agreement(
+        &self,
+        kid: &str,
+        public_key: &[u8],
+    ) -> Result<Zeroizing<[u8; 32]>, Box<dyn Error>>;
+
+    /// EdDSA signature of `payload` by the Ed25519 key `kid`.
+    async fn sign(&self, kid: &str, payload: &[u8]) -> Result<Vec<u8>, Box<dyn Error>>;
+
+    /// Signed JWE of `message` from the key `kid`. didcomm-rs derives the
+    /// content encryption key itself, so sealing stays behind the store.
+    async fn seal(
+        &self,
+        kid: &str,
+        message: &Message,
+        recipient_public_key: Vec<u8>,
+    ) -> Result<String, Box<dyn Error>>;
+
+    async fn open(
+        &self,
+        kid: &str,
+        raw: &str,
+        sender_public_key: Vec<u8>,
+    ) -> Result<Message, Box<dyn Error>>;
+}
+
+fn did_of(key: &KeyPair) -> String {
+    key.get_did_document(Default::default()).id
+}
+
+fn find<'a>(keys: &'a [KeyPair], kid: &str) -> Result<&'a KeyPair, Box<dyn Error>> {
+    let did = kid.split('#').next().unwrap_or_default();
+    keys.iter()
+        .find(|key| did_of(key) == did)
+        .ok_or_else(|| format!("unknown key {}", kid).into())
+}
+
+fn key_agreement(key: &KeyPair, public_key: &[u8]) -> Result<Zeroizing<[u8; 32]>, Box<dyn Error>> {
+    if !did_of(key).starts_with(X25519_PREFIX) {
+        return Err("key agreement needs an x25519 key".into());
+    }
+    let secret = StaticSecret::from(<[u8; 32]>::try_from(key.private_key_bytes().as_slice())?);
+    let public = X25519PublicKey::from(<[u8; 32]>::try_from(public_key)?);
+    Ok(Zeroizing::new(secret.diffie_hellman(&public).to_bytes()))
+}
+
+fn sign(key: &KeyPair, payload: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !did_of(key).starts_with(ED25519_PREFIX) {
+        return Err("signing needs an ed25519 key".into());
+    }
+    let private = Zeroizing::new(key.private_key_bytes());
+    let secret = SecretKey::from_bytes(&private)?;
+    let public = PublicKey::from(&secret);
+    Ok(ExpandedSecretKey::from(&secret)
+        .sign(payload, &public)
+        .to_bytes()
+        .to_vec())
+}
+
+fn seal(
+    key: &KeyPair,
+    message: &Message,
+    recipient_public_key: Vec<u8>,
+) -> Result<String, Box<dyn Error>> {
+    let sign_key = generate::<Ed25519KeyPair>(None);
+    let message = message
+        .clone()
+        .as_jwe(&CryptoAlgorithm::XC20P, Some(recipient_public_key.to_vec()))
+        .kid(&hex::encode(sign_key.public_key_bytes()));
+    Ok(message.seal_signed(
+        &key.private_key_bytes(),
+        Some(vec![Some(recipient_public_key)]),
+        SignatureAlgorithm::EdDsa,
+        &[sign_key.private_key_bytes(), sign_key.public_key_bytes()].concat(),
+    )?)
+}
+
+async fn open(
+    key: &KeyPair,
+    raw: &str,
+    sender_public_key: Vec<u8>,
+) -> Result<Message, Box<dyn Error>> {
+    Ok(receive(
+        raw,
+        Some(&key.private_key_bytes()),
+        Some(sender_public_key),
+        None,
+    )
+    .await?)
+}
+
+/// A single did:key, used by clients and tests.
+#[async_trait]
+impl KeyStore for KeyPair {
+    fn did(&self) -> String {
+        did_of(self)
+    }
+
+    fn key_ids(&self) -> Vec<String> {
+        vec![key_id(&self.did())]
+    }
+
+    async fn key_agreement(
+        &self,
+        kid: &str,
+        public_key: &[u8],
+    ) -> Result<Zeroizing<[u8; 32]>, Box<dyn Error>> {
+        key_agreement(find(std::slice::from_ref(self), kid)?, public_key)
+    }
+
+    async fn sign(&self, kid: &str, payload: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        sign(find(std::slice::from_ref(self), kid)?, payload)
+    }
+
+    async fn seal(
+        &self,
+        kid: &str,
+        message: &Message,
+        recipient_public_key: Vec<u8>,
+    ) -> Result<String, Box<dyn Error>> {
+        seal(
+            find(std::slice::from_ref(self), kid)?,
+            message,
+            recipient_public_key,
+        )
+    }
+
+    async fn open(
+        &self,
+        kid: &str,
+        raw: &str,
+        sender_public_key: Vec<u8>,
+    ) -> Result<Message, Box<dyn Error>> {
+        let key = find(std::slice::from_ref(self), kid)?;
+        open(key, raw, sender_public_key).await
+    }
+}
+
+/// Software key store of the mediator: the current key and the retired keys
+/// still within their grace period.
+#[async_trait]
+impl KeyStore for Wallet {
+    fn did(&self) -> String {
+        self.did_key()
+    }
+
+    fn key_ids(&self) -> Vec<String> {
+        self.keypairs()
+            .iter()
+            .map(|key| key_id(&did_of(key)))
+            .collect()
+    }
+
+    async fn key_agreement(
+        &self,
+        kid: &str,
+        public_key: &[u8],
+    ) -> Result<Zeroizing<[u8; 32]>, Box<dyn Error>> {
+        key_agreement(find(&self.keypairs(), kid)?, public_key)
+    }
+
+    async fn sign(&self, kid: &str, payload: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        sign(find(&self.signing_keypairs(), kid)?, payload)
+    }
+
+    async fn seal(
+        &self,
+        kid: &str,
+        message: &Message,
+        recipient_public_key: Vec<u8>,
+    ) -> Result<String, Box<dyn Error>> {
+        seal(find(&self.keypairs(), kid)?, message, recipient_public_key)
+    }
+
+    async fn open(
+        &self,
+        kid: &str,
+        raw: &str,
+        sender_public_key: Vec<u8>,
+    ) -> Result<Message, Box<dyn Error>> {
+        let keys = self.keypairs();
+        let key = find(&keys, kid)?;
+        open(key, raw, sender_public_key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use did_key::X25519KeyPair;
+
+    #[tokio::test]
+    async fn test_key_agreement() {
+        let alice = Wallet::default();
+        let bob = generate::<X25519KeyPair>(None);
+        let alice_kid = alice.key_ids().remove(0);
+        let bob_kid = bob.key_ids().remove(0);
+
+        let shared = alice
+            .key_agreement(&alice_kid, &bob.public_key_bytes())
+            .await
+            .unwrap();
+        let other = bob
+            .key_agreement(&bob_kid, &alice.keypair().public_key_bytes())
+            .await
+            .unwrap();
+        assert_eq!(*shared, *other);
+        assert!(alice.key_agreement(&bob_kid, &[0u8; 32]).await.is_err());
+
+        let ed25519 = generate::<Ed25519KeyPair>(None);
+        assert!(ed25519
+            .key_agreement(&ed25519.key_ids()[0], &bob.public_key_bytes())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retired_key_ids() {
+        let wallet = Wallet::default();
+        let old = wallet.key_ids();
+        wallet.rotate().unwrap();
+        let kids = wallet.key_ids();
+        assert_eq!(kids.len(), 2);
+        assert_eq!(kids[0], key_id(&wallet.did_key()));
+        assert_eq!(kids[1], old[0]);
+        assert!(wallet.sign(&old[0], b"payload").await.is_err());
+        let signing = key_id(&wallet.signing_did());
+        assert!(wallet.sign(&signing, b"payload").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_seal_open() {
+        let alice = generate::<X25519KeyPair>(None);
+        let bob = Wallet::default();
+        let message = Message::new()
+            .from(&alice.did())
+            .to(&[&bob.did()])
+            .body(r#"{"foo":"bar"}"#);
+        let sealed = alice
+            .seal(
+                &alice.key_ids()[0],
+                &message,
+                bob.keypair().public_key_bytes(),
+            )
+            .await
+            .unwrap();
+        let opened = bob
+            .open(&bob.key_ids()[0], &sealed, alice.public_key_bytes())
+            .await
+            .unwrap();
+        assert_eq!(opened.get_body().unwrap(), r#"{"foo":"bar"}"#);
+    }
+}
@@ -1,14 +1,32 @@
 pub mod config;
 pub mod connections;
+pub mod cors;
 pub mod diddoc;
 pub mod didweb;
+pub mod events;
+pub mod fromprior;
 pub mod handler;
+pub mod instrumentation;
+pub mod interceptor;
 pub mod keybytes;
+#[cfg(feature = "keyfile")]
+pub mod keyfile;
+pub mod keystore;
+pub mod live;
+pub mod mediator;
 pub mod message;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod protocols;
+pub mod push;
+pub mod ratelimit;
+pub mod registry;
 pub mod resolver;
 pub mod service;
+pub mod tenants;
 pub mod wallet;
+#[cfg(feature = "webhooks")]
+pub mod webhook;
 
 #[cfg(test)]
 mod tests;
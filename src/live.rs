@@ -0,0 +1,134 @@
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+struct LiveSession {
+    id: u64,
+    sender: UnboundedSender<Value>,
+    live: bool,
+}
+
+#[derive(Default)]
+pub struct LiveSessions {
+    sessions: Mutex<HashMap<String, LiveSession>>,
+    next_id: AtomicU64,
+}
+
+impl LiveSessions {
+    pub fn new() -> Self {
+        LiveSessions::default()
+    }
+
+    /// Replaces any earlier session of `did`. The returned id closes only
+    /// this session, so a stale socket can't end the one replacing it.
+    pub fn connect(&self, did: &str) -> (u64, UnboundedReceiver<Value>) {
+        let (sender, receiver) = unbounded();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.sessions.lock().unwrap().insert(
+            did.to_string(),
+            LiveSession {
+                id,
+                sender,
+                live: false,
+            },
+        );
+        (id, receiver)
+    }
+
+    pub fn close(&self, did: &str, id: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.get(did).map(|session| session.id) == Some(id) {
+            sessions.remove(did);
+        }
+    }
+
+    pub fn disconnect(&self, did: &str) {
+        self.sessions.lock().unwrap().remove(did);
+    }
+
+    pub fn is_connected(&self, did: &str) -> bool {
+        self.sessions.lock().unwrap().contains_key(did)
+    }
+
+    pub fn set_live(&self, did: &str, live: bool) -> bool {
+        match self.sessions.lock().unwrap().get_mut(did) {
+            Some(session) => {
+                session.live = live;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_live(&self, did: &str) -> bool {
+        match self.sessions.lock().unwrap().get(did) {
+            Some(session) => session.live && !session.sender.is_closed(),
+            None => false,
+        }
+    }
+
+    pub fn deliver(&self, did: &str, message: Value) -> Result<(), Value> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(did) {
+            Some(session) if session.live => match session.sender.unbounded_send(message) {
+                Ok(()) => Ok(()),
+                Err(error) => {
+                    sessions.remove(did);
+                    Err(error.into_inner())
+                }
+            },
+            _ => Err(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_deliver() {
+        let sessions = LiveSessions::new();
+        assert!(sessions.deliver("did:test", json!({})).is_err());
+
+        let (_, mut receiver) = sessions.connect("did:test");
+        assert!(sessions.is_connected("did:test"));
+        assert!(sessions.deliver("did:test", json!({})).is_err());
+
+        assert!(sessions.set_live("did:test", true));
+        assert!(sessions.is_live("did:test"));
+        sessions.deliver("did:test", json!({"foo": "bar"})).unwrap();
+        assert_eq!(receiver.next().await.unwrap(), json!({"foo": "bar"}));
+
+        sessions.disconnect("did:test");
+        assert!(!sessions.is_live("did:test"));
+        assert!(!sessions.set_live("did:test", true));
+    }
+
+    #[test]
+    fn test_closed_receiver() {
+        let sessions = LiveSessions::new();
+        let (_, receiver) = sessions.connect("did:test");
+        sessions.set_live("did:test", true);
+        drop(receiver);
+        assert!(sessions.deliver("did:test", json!({})).is_err());
+        assert!(!sessions.is_connected("did:test"));
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let sessions = LiveSessions::new();
+        let (old, _old_receiver) = sessions.connect("did:test");
+        let (new, _receiver) = sessions.connect("did:test");
+        sessions.set_live("did:test", true);
+
+        sessions.close("did:test", old);
+        assert!(sessions.is_live("did:test"));
+        sessions.close("did:test", new);
+        assert!(!sessions.is_connected("did:test"));
+    }
+}
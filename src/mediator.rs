@@ -0,0 +1,1224 @@
+use crate::config::MessageLimits;
+use crate::connections::ConnectionStorage;
+use crate::events::{EventListener, MediatorEvent};
+use crate::fromprior::FromPrior;
+use crate::handler::HandlerResponse;
+use crate::instrumentation::{Instrumentation, NoInstrumentation};
+use crate::interceptor::Interceptor;
+use crate::keystore::KeyStore;
+use crate::live::LiveSessions;
+use crate::message::{sign_and_encrypt, Transport};
+use crate::protocols::messagepickup::{batch_messages, BATCH};
+use crate::push::PushNotifier;
+use crate::ratelimit::RateLimitExceeded;
+use crate::registry::HandlerRegistry;
+use crate::resolver::{DefaultResolver, DidResolver};
+use crate::wallet::Wallet;
+use chrono::{DateTime, Duration, Utc};
+use didcomm_rs::{Jwe, Message};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use tracing::field::Empty;
+use tracing::{debug, info, info_span, warn, Instrument, Span};
+
+const RESOLVER_CACHE_SIZE: usize = 1024;
+const RESOLVER_CACHE_TTL_SECS: i64 = 3600;
+
+struct ResolvedKey {
+    key: Vec<u8>,
+    resolved_at: DateTime<Utc>,
+    used_at: DateTime<Utc>,
+}
+
+/// Resolved keys, dropped after `RESOLVER_CACHE_TTL_SECS` so rotated DID documents are
+/// picked up, the least recently used one evicted when full.
+#[derive(Default)]
+struct ResolverCache {
+    entries: HashMap<String, ResolvedKey>,
+}
+
+impl ResolverCache {
+    fn get(&mut self, did: &str, now: DateTime<Utc>) -> Option<Vec<u8>> {
+        let entry = self.entries.get_mut(did)?;
+        if now - entry.resolved_at >= Duration::seconds(RESOLVER_CACHE_TTL_SECS) {
+            self.entries.remove(did);
+            return None;
+        }
+        entry.used_at = now;
+        Some(entry.key.clone())
+    }
+
+    fn insert(&mut self, did: &str, key: Vec<u8>, now: DateTime<Utc>) {
+        if self.entries.len() >= RESOLVER_CACHE_SIZE && !self.entries.contains_key(did) {
+            let least_used = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used_at)
+                .map(|(did, _)| did.clone());
+            if let Some(least_used) = least_used {
+                self.entries.remove(&least_used);
+            }
+        }
+        self.entries.insert(
+            did.to_string(),
+            ResolvedKey {
+                key,
+                resolved_at: now,
+                used_at: now,
+            },
+        );
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MediatorOutput {
+    Response(Value),
+    Empty,
+    BadRequest(String),
+    TooManyRequests,
+    InternalError(String),
+}
+
+/// A message decrypted by `receive`, with the DID of the `skid` that sent it.
+#[derive(Clone)]
+pub struct Received {
+    pub message: Message,
+    pub sender: String,
+}
+
+pub struct Mediator {
+    wallet: Wallet,
+    connections: Arc<dyn ConnectionStorage>,
+    resolver: Box<dyn DidResolver>,
+    registry: HandlerRegistry,
+    interceptors: Vec<Box<dyn Interceptor>>,
+    live: LiveSessions,
+    instrumentation: Arc<dyn Instrumentation>,
+    listeners: Vec<Arc<dyn EventListener>>,
+    push_notifier: Option<Arc<dyn PushNotifier>>,
+    limits: MessageLimits,
+    resolved: Mutex<ResolverCache>,
+}
+
+impl Mediator {
+    pub fn new(wallet: Wallet, connections: Arc<dyn ConnectionStorage>) -> Self {
+        Mediator {
+            wallet,
+            connections,
+            resolver: Box::new(DefaultResolver::default()),
+            registry: HandlerRegistry::with_defaults(),
+            interceptors: Vec::new(),
+            live: LiveSessions::new(),
+            instrumentation: Arc::new(NoInstrumentation::default()),
+            listeners: Vec::new(),
+            push_notifier: None,
+            limits: MessageLimits::default(),
+            resolved: Mutex::new(ResolverCache::default()),
+        }
+    }
+
+    pub fn resolver(mut self, resolver: Box<dyn DidResolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    pub fn registry(mut self, registry: HandlerRegistry) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    pub fn interceptor(mut self, interceptor: Box<dyn Interceptor>) -> Self {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    pub fn instrumentation(mut self, instrumentation: Arc<dyn Instrumentation>) -> Self {
+        self.instrumentation = instrumentation;
+        self
+    }
+
+    pub fn listener(mut self, listener: Arc<dyn EventListener>) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    pub fn push_notifier(mut self, push_notifier: Arc<dyn PushNotifier>) -> Self {
+        self.push_notifier = Some(push_notifier);
+        self
+    }
+
+    pub fn limits(mut self, limits: MessageLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn wallet(&self) -> &Wallet {
+        &self.wallet
+    }
+
+    pub fn connections(&self) -> &Arc<dyn ConnectionStorage> {
+        &self.connections
+    }
+
+    pub fn live(&self) -> &LiveSessions {
+        &self.live
+    }
+
+    pub async fn process(&self, raw: &str) -> MediatorOutput {
+        async {
+            match self.receive(raw).await {
+                Ok(received) => self.handle(&received).await,
+                Err(error) => {
+                    warn!(%error, "could not receive message");
+                    MediatorOutput::BadRequest(error)
+                }
+            }
+        }
+        .instrument(Self::span())
+        .await
+    }
+
+    /// Span of one inbound message. It is opened before `receive`, so that
+    /// decryption is traced too; `handle` records the header once known.
+    pub fn span() -> Span {
+        info_span!(
+            "message",
+            id = Empty,
+            m_type = Empty,
+            thid = Empty,
+            from = Empty
+        )
+    }
+
+    pub async fn receive(&self, raw: &str) -> Result<Received, String> {
+        if raw.len() > self.limits.max_envelope_size {
+            return Err(format!(
+                "envelope of {} bytes exceeds max_envelope_size of {} bytes",
+                raw.len(),
+                self.limits.max_envelope_size
+            ));
+        }
+        let jwe: Jwe = serde_json::from_str(raw).map_err(|error| error.to_string())?;
+        let skid = jwe.get_skid().ok_or_else(|| "skid missing".to_string())?;
+        let sender = skid.split('#').next().unwrap_or_default().to_string();
+        let sender_public_key = self.resolve(&skid).await?;
+        let mut received = Err("no key".to_string());
+        for (index, kid) in self.wallet.key_ids().iter().enumerate() {
+            received = self
+                .wallet
+                .open(kid, raw, sender_public_key.clone())
+                .await
+                .map_err(|error| error.to_string());
+            if received.is_ok() {
+                if index > 0 {
+                    debug!(retired = index, "decrypted with a retired key");
+                }
+                break;
+            }
+        }
+        received
+            .map_err(|error| {
+                self.instrumentation.decrypt_failure();
+                error
+            })
+            .and_then(|message| self.check_attachments(message))
+            .and_then(|message| Self::check_sender(message, &sender))
+            .map(|message| Received { message, sender })
+    }
+
+    /// Decrypting only authenticates the `skid`, so `from` has to name the
+    /// same DID. Past `receive`, `from` is the authenticated sender.
+    fn check_sender(message: Message, sender: &str) -> Result<Message, String> {
+        match &message.get_didcomm_header().from {
+            Some(from) if from != sender => {
+                Err(format!("from {} does not match skid {}", from, sender))
+            }
+            Some(_) => Ok(message),
+            None => Ok(message.from(sender)),
+        }
+    }
+
+    fn check_attachments(&self, message: Message) -> Result<Message, String> {
+        let count = message.get_attachments().count();
+        if count > self.limits.max_attachments {
+            return Err(format!(
+                "{} attachments exceed max_attachments of {}",
+                count, self.limits.max_attachments
+            ));
+        }
+        for attachment in message.get_attachments() {
+            let size = attachment.data.json.as_ref().map_or(0, |json| json.len())
+                + attachment
+                    .data
+                    .base64
+                    .as_ref()
+                    .map_or(0, |base64| base64.len());
+            if size > self.limits.max_attachment_size {
+                return Err(format!(
+                    "attachment of {} bytes exceeds max_attachment_size of {} bytes",
+                    size, self.limits.max_attachment_size
+                ));
+            }
+        }
+        Ok(message)
+    }
+
+    async fn resolve(&self, did: &str) -> Result<Vec<u8>, String> {
+        let started = Utc::now();
+        let cached = self.resolved.lock().unwrap().get(did, started);
+        if let Some(key) = cached {
+            self.instrumentation
+                .resolved((Utc::now() - started).to_std().unwrap_or_default(), true);
+            return Ok(key);
+        }
+        let key = self
+            .resolver
+            .resolve(did)
+            .await
+            .map_err(|error| format!("{:?}", error))?;
+        self.instrumentation
+            .resolved((Utc::now() - started).to_std().unwrap_or_default(), false);
+        self.resolved
+            .lock()
+            .unwrap()
+            .insert(did, key.clone(), Utc::now());
+        Ok(key)
+    }
+
+    async fn dispatch(&self, request: &mut Message) -> Result<HandlerResponse, Box<dyn Error>> {
+        let protocol = self
+            .registry
+            .protocol(&request.get_didcomm_header().m_type)
+            .unwrap_or_else(|| "unknown".to_string());
+        self.instrumentation.inbound(&protocol);
+        let mut short_circuit = None;
+        for interceptor in &self.interceptors {
+            let response = interceptor.before_handle(request).await?;
+            if response.is_some() {
+                short_circuit = response;
+                break;
+            }
+        }
+        let mut response = match short_circuit {
+            Some(response) => response,
+            None => match self.registry.get(&request.get_didcomm_header().m_type) {
+                Some(handler) => {
+                    handler
+                        .handle(request, Some(&self.wallet), Some(&self.connections))
+                        .await?
+                }
+                None => HandlerResponse::Skipped,
+            },
+        };
+        for interceptor in self.interceptors.iter().rev() {
+            response = interceptor.after_handle(request, response).await?;
+        }
+        Ok(response)
+    }
+
+    /// Handles a message returned by `receive`, inside the `span` opened
+    /// for it.
+    pub async fn handle(&self, received: &Received) -> MediatorOutput {
+        let header = received.message.get_didcomm_header();
+        let span = Span::current();
+        span.record("id", &tracing::field::display(&header.id));
+        span.record("m_type", &tracing::field::display(&header.m_type));
+        span.record("thid", &tracing::field::debug(&header.thid));
+        span.record("from", &tracing::field::debug(&header.from));
+        self.handle_message(received).await
+    }
+
+    /// Moves the connection of a sender that rotated its DID, after checking
+    /// its `from_prior` was signed by the previous DID and rotated to the
+    /// DID that sealed the message.
+    async fn rotate_sender(&self, received: &Received) -> Result<(), String> {
+        let jwt = match FromPrior::from_message(&received.message) {
+            Some(jwt) => jwt,
+            None => return Ok(()),
+        };
+        let prior = FromPrior::verify(&jwt).map_err(|error| error.to_string())?;
+        if prior.sub != received.sender {
+            return Err("from_prior sub does not match skid".to_string());
+        }
+        if self
+            .connections
+            .migrate(prior.iss.to_string(), prior.sub.to_string())
+            .await
+        {
+            info!(from = %prior.iss, to = %prior.sub, "sender rotated did");
+        }
+        Ok(())
+    }
+
+    async fn outgoing(&self, message: &Message) -> Message {
+        match self.wallet.from_prior().await {
+            Some(jwt) => FromPrior::apply(jwt, message.clone()),
+            None => message.clone(),
+        }
+    }
+
+    async fn handle_message(&self, received: &Received) -> MediatorOutput {
+        if let Err(error) = self.rotate_sender(received).await {
+            warn!(%error, "invalid from_prior");
+            return MediatorOutput::BadRequest(error);
+        }
+        let mut request = received.message.clone();
+        let handled = match self.dispatch(&mut request).await {
+            Ok(handled) => handled,
+            Err(error) if error.is::<RateLimitExceeded>() => {
+                warn!("rate limit exceeded");
+                return MediatorOutput::TooManyRequests;
+            }
+            Err(error) => {
+                let error = error.to_string();
+                warn!(%error, "handler failed");
+                return MediatorOutput::BadRequest(error);
+            }
+        };
+        debug!(outcome = handled.outcome(), "handled message");
+        let received = &request;
+        if let Some(event) = Self::event(received, &handled) {
+            self.emit(event).await;
+        }
+        let transport = Transport::from_message(received);
+        let mut reply = None;
+        match handled {
+            HandlerResponse::Skipped => {}
+            HandlerResponse::Processed => {}
+            HandlerResponse::Forward(receivers, message) => {
+                for receiver in receivers {
+                    if self.enqueue(*message.clone(), receiver).await {
+                        self.instrumentation.forward_queued();
+                    }
+                }
+            }
+            HandlerResponse::Send(to, message) => match &transport {
+                Some(transport) if transport.returns(received, &message) => {
+                    reply = Some((to, message));
+                }
+                _ => {
+                    self.enqueue(*message, to).await;
+                }
+            },
+            HandlerResponse::Reply(to, message) => reply = Some((to, message)),
+            HandlerResponse::Response(product) => return MediatorOutput::Response(product),
+            HandlerResponse::LiveDelivery(did, live) => {
+                if !self.live.set_live(&did, live) {
+                    return MediatorOutput::BadRequest(
+                        "live delivery requires a websocket session".to_string(),
+                    );
+                }
+                if live {
+                    while let Some(message) = self.connections.get_next(did.to_string()).await {
+                        if !self.deliver_live(&message, &did).await {
+                            self.connections
+                                .requeue(vec![message], did.to_string())
+                                .await;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        match reply {
+            Some((to, message)) => self.reply(&message, &to).await,
+            None => MediatorOutput::Empty,
+        }
+    }
+
+    /// Seals a direct reply. Messages a pickup batch took from the queue go
+    /// back in front of it when sealing fails.
+    async fn reply(&self, reply: &Message, did: &str) -> MediatorOutput {
+        let sealed = sign_and_encrypt(
+            &self.outgoing(reply).await,
+            &self.wallet.did_key(),
+            did,
+            &self.wallet,
+        )
+        .await
+        .map_err(|error| error.to_string());
+        self.instrumentation
+            .delivery_attempt("return-route", sealed.is_ok());
+        match sealed {
+            Ok(response) => MediatorOutput::Response(response),
+            Err(error) => {
+                warn!(%error, "could not seal reply");
+                if reply.get_didcomm_header().m_type == BATCH {
+                    self.connections
+                        .requeue(batch_messages(reply), did.to_string())
+                        .await;
+                }
+                MediatorOutput::InternalError(error)
+            }
+        }
+    }
+
+    async fn deliver_live(&self, message: &Message, did: &str) -> bool {
+        if !self.live.is_live(did) {
+            return false;
+        }
+        let packed = sign_and_encrypt(
+            &self.outgoing(message).await,
+            &self.wallet.did_key(),
+            did,
+            &self.wallet,
+        )
+        .await
+        .ok();
+        let delivered = match packed {
+            Some(packed) => self.live.deliver(did, packed).is_ok(),
+            None => false,
+        };
+        self.instrumentation.delivery_attempt("live", delivered);
+        delivered
+    }
+
+    /// Delivers `message` live when possible, queueing it otherwise. Returns
+    /// whether it was queued.
+    async fn enqueue(&self, message: Message, did: String) -> bool {
+        if self.deliver_live(&message, &did).await {
+            return false;
+        }
+        let message_id = message.get_didcomm_header().id.to_string();
+        self.connections
+            .insert_message_for(message, did.to_string())
+            .await;
+        if let Some(push_notifier) = &self.push_notifier {
+            if let Some(device) = self.connections.get_device(did.to_string()).await {
+                push_notifier.notify(&did, &device).await;
+            }
+        }
+        self.emit(MediatorEvent::MessageQueued { did, message_id })
+            .await;
+        true
+    }
+
+    fn event(request: &Message, handled: &HandlerResponse) -> Option<MediatorEvent> {
+        let header = request.get_didcomm_header();
+        let did = header.from.clone()?;
+        match (header.m_type.as_str(), handled) {
+            ("https://didcomm.org/didexchange/1.0/request", HandlerResponse::Send(_, _)) => {
+                Some(MediatorEvent::MediationGranted { did })
+            }
+            ("https://didcomm.org/didexchange/1.0/complete", HandlerResponse::Processed) => {
+                Some(MediatorEvent::ConnectionCompleted { did })
+            }
+            ("https://didcomm.org/basicmessage/2.0/message", HandlerResponse::Processed) => {
+                Some(MediatorEvent::BasicMessageReceived {
+                    did,
+                    message_id: header.id.to_string(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    async fn emit(&self, event: MediatorEvent) {
+        for listener in &self.listeners {
+            listener.notify(&event).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connections::Connections;
+    use crate::message::{add_return_route_all_header, ReturnRoute};
+    use crate::protocols::trustping::TrustPingResponseBuilder;
+    use did_key::{generate, DIDCore, Ed25519KeyPair, KeyMaterial, X25519KeyPair};
+
+    fn mediator() -> Mediator {
+        Mediator::new(Wallet::default(), Arc::new(Connections::new()))
+    }
+
+    #[tokio::test]
+    async fn test_process_invalid() {
+        let mediator = mediator();
+        assert!(matches!(
+            mediator.process("{}").await,
+            MediatorOutput::BadRequest(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_process_anoncrypt() {
+        let mediator = mediator();
+        let key = generate::<X25519KeyPair>(None);
+        let ping = TrustPingResponseBuilder::new()
+            .build()
+            .unwrap()
+            .to(&[&mediator.wallet().did_key()]);
+        let request = key
+            .seal(
+                &key.key_ids()[0],
+                &ping,
+                mediator.wallet().keypair().public_key_bytes(),
+            )
+            .await
+            .unwrap();
+        match mediator.process(&request).await {
+            MediatorOutput::BadRequest(error) => assert!(error.contains("skid")),
+            output => panic!("unexpected {:?}", output),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_trust_ping() {
+        let mediator = mediator();
+        let key = generate::<X25519KeyPair>(None);
+        let did_from = key.get_did_document(Default::default()).id;
+
+        let ping = TrustPingResponseBuilder::new().build().unwrap();
+        let request = sign_and_encrypt(&ping, &did_from, &mediator.wallet().did_key(), &key)
+            .await
+            .unwrap();
+        let output = mediator
+            .process(&serde_json::to_string(&request).unwrap())
+            .await;
+        assert_eq!(output, MediatorOutput::Empty);
+        assert_eq!(
+            mediator
+                .connections()
+                .get(did_from.to_string())
+                .await
+                .unwrap()
+                .messages
+                .len(),
+            1
+        );
+
+        let ping = add_return_route_all_header(TrustPingResponseBuilder::new().build().unwrap());
+        let request = sign_and_encrypt(&ping, &did_from, &mediator.wallet().did_key(), &key)
+            .await
+            .unwrap();
+        let output = mediator
+            .process(&serde_json::to_string(&request).unwrap())
+            .await;
+        match output {
+            MediatorOutput::Response(response) => {
+                let received = Message::receive(
+                    &serde_json::to_string(&response).unwrap(),
+                    Some(&key.private_key_bytes()),
+                    None,
+                    None,
+                )
+                .unwrap();
+                assert_eq!(
+                    received.get_didcomm_header().m_type,
+                    "https://didcomm.org/trust-ping/2.0/ping-response"
+                );
+            }
+            _ => panic!("expected response"),
+        }
+        assert_eq!(
+            mediator
+                .connections()
+                .get(did_from.to_string())
+                .await
+                .unwrap()
+                .messages
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pickup_batch_limit() {
+        use crate::protocols::messagepickup::{MessagePickupHandler, MessagePickupResponseBuilder};
+
+        let mut registry = HandlerRegistry::with_defaults();
+        registry.register(
+            "messagepickup",
+            "1.0",
+            Box::new(MessagePickupHandler::new(2)),
+        );
+        let mediator = mediator().registry(registry);
+        let key = generate::<X25519KeyPair>(None);
+        let did_from = key.get_did_document(Default::default()).id;
+        for _ in 0..3 {
+            mediator
+                .connections()
+                .insert_message_for(Message::new(), did_from.to_string())
+                .await;
+        }
+
+        let pickup = MessagePickupResponseBuilder::new()
+            .batch_size(10)
+            .build_batch_pickup()
+            .unwrap();
+        let request = sign_and_encrypt(&pickup, &did_from, &mediator.wallet().did_key(), &key)
+            .await
+            .unwrap();
+        match mediator
+            .process(&serde_json::to_string(&request).unwrap())
+            .await
+        {
+            MediatorOutput::Response(response) => {
+                let received = Message::receive(
+                    &serde_json::to_string(&response).unwrap(),
+                    Some(&key.private_key_bytes()),
+                    None,
+                    None,
+                )
+                .unwrap();
+                assert_eq!(received.get_didcomm_header().m_type, BATCH);
+                assert_eq!(batch_messages(&received).len(), 2);
+            }
+            output => panic!("unexpected {:?}", output),
+        }
+        assert_eq!(
+            mediator
+                .connections()
+                .get(did_from)
+                .await
+                .unwrap()
+                .messages
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pickup_from_prior() {
+        use crate::protocols::messagepickup::MessagePickupResponseBuilder;
+
+        let mediator = mediator();
+        let key = generate::<X25519KeyPair>(None);
+        let did_from = key.get_did_document(Default::default()).id;
+        mediator
+            .connections()
+            .insert_message_for(Message::new(), did_from.to_string())
+            .await;
+        let prior = mediator.wallet().signing_did();
+        mediator.wallet().rotate().unwrap();
+
+        let pickup = MessagePickupResponseBuilder::new()
+            .batch_size(10)
+            .build_batch_pickup()
+            .unwrap();
+        let request = sign_and_encrypt(&pickup, &did_from, &mediator.wallet().did_key(), &key)
+            .await
+            .unwrap();
+        match mediator
+            .process(&serde_json::to_string(&request).unwrap())
+            .await
+        {
+            MediatorOutput::Response(response) => {
+                let received = Message::receive(
+                    &serde_json::to_string(&response).unwrap(),
+                    Some(&key.private_key_bytes()),
+                    None,
+                    None,
+                )
+                .unwrap();
+                assert_eq!(received.get_didcomm_header().m_type, BATCH);
+                let jwt = FromPrior::from_message(&received).unwrap();
+                let rotated = FromPrior::verify(&jwt).unwrap();
+                assert_eq!(rotated.iss, prior);
+                assert_eq!(rotated.sub, mediator.wallet().did_key());
+            }
+            output => panic!("unexpected {:?}", output),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retired_key() {
+        let key = generate::<X25519KeyPair>(None);
+        let did_from = key.get_did_document(Default::default()).id;
+        let ping = TrustPingResponseBuilder::new().build().unwrap();
+
+        let mediator = mediator();
+        let old_did = mediator.wallet().did_key();
+        mediator.wallet().rotate().unwrap();
+        let request = sign_and_encrypt(&ping, &did_from, &old_did, &key)
+            .await
+            .unwrap();
+        let request = serde_json::to_string(&request).unwrap();
+        assert_eq!(mediator.process(&request).await, MediatorOutput::Empty);
+
+        let mediator = Mediator::new(
+            Wallet::default().grace_period(0),
+            Arc::new(Connections::new()),
+        );
+        let old_did = mediator.wallet().did_key();
+        mediator.wallet().rotate().unwrap();
+        let request = sign_and_encrypt(&ping, &did_from, &old_did, &key)
+            .await
+            .unwrap();
+        let request = serde_json::to_string(&request).unwrap();
+        assert!(matches!(
+            mediator.process(&request).await,
+            MediatorOutput::BadRequest(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_from_prior() {
+        let mediator = mediator();
+        let old_key = generate::<Ed25519KeyPair>(None);
+        let old_did = old_key.get_did_document(Default::default()).id;
+        let key = generate::<X25519KeyPair>(None);
+        let did_from = key.get_did_document(Default::default()).id;
+        let other = generate::<X25519KeyPair>(None)
+            .get_did_document(Default::default())
+            .id;
+        mediator
+            .connections()
+            .insert_message_for(Message::new(), old_did.to_string())
+            .await;
+
+        let forged = FromPrior::new(&old_did, &other)
+            .sign(&old_key)
+            .await
+            .unwrap();
+        let ping = FromPrior::apply(forged, TrustPingResponseBuilder::new().build().unwrap());
+        let request = sign_and_encrypt(&ping, &did_from, &mediator.wallet().did_key(), &key)
+            .await
+            .unwrap();
+        let request = serde_json::to_string(&request).unwrap();
+        assert!(matches!(
+            mediator.process(&request).await,
+            MediatorOutput::BadRequest(_)
+        ));
+
+        let mediator_did = mediator.wallet().did_key();
+        let mediator_signing_did = mediator.wallet().signing_did();
+        mediator.wallet().rotate().unwrap();
+        let from_prior = FromPrior::new(&old_did, &did_from)
+            .sign(&old_key)
+            .await
+            .unwrap();
+        let ping = add_return_route_all_header(FromPrior::apply(
+            from_prior,
+            TrustPingResponseBuilder::new().build().unwrap(),
+        ));
+        let request = sign_and_encrypt(&ping, &did_from, &mediator_did, &key)
+            .await
+            .unwrap();
+        let output = mediator
+            .process(&serde_json::to_string(&request).unwrap())
+            .await;
+        assert!(mediator.connections().get(old_did).await.is_none());
+        assert_eq!(
+            mediator
+                .connections()
+                .get(did_from.to_string())
+                .await
+                .unwrap()
+                .messages
+                .len(),
+            1
+        );
+        match output {
+            MediatorOutput::Response(response) => {
+                let received = Message::receive(
+                    &serde_json::to_string(&response).unwrap(),
+                    Some(&key.private_key_bytes()),
+                    None,
+                    None,
+                )
+                .unwrap();
+                let jwt = FromPrior::from_message(&received).unwrap();
+                let rotated = FromPrior::verify(&jwt).unwrap();
+                assert_eq!(rotated.iss, mediator_signing_did);
+                assert_eq!(rotated.sub, mediator.wallet().did_key());
+            }
+            _ => panic!("expected response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_return_route_thread() {
+        let mediator = mediator();
+        let key = generate::<X25519KeyPair>(None);
+        let did_from = key.get_did_document(Default::default()).id;
+
+        let ping = Transport::thread("other".to_string())
+            .apply(TrustPingResponseBuilder::new().build().unwrap());
+        let request = sign_and_encrypt(&ping, &did_from, &mediator.wallet().did_key(), &key)
+            .await
+            .unwrap();
+        let output = mediator
+            .process(&serde_json::to_string(&request).unwrap())
+            .await;
+        assert_eq!(output, MediatorOutput::Empty);
+
+        let ping = Transport::new(ReturnRoute::Thread)
+            .apply(TrustPingResponseBuilder::new().build().unwrap());
+        let request = sign_and_encrypt(&ping, &did_from, &mediator.wallet().did_key(), &key)
+            .await
+            .unwrap();
+        let output = mediator
+            .process(&serde_json::to_string(&request).unwrap())
+            .await;
+        match output {
+            MediatorOutput::Response(response) => {
+                let received = Message::receive(
+                    &serde_json::to_string(&response).unwrap(),
+                    Some(&key.private_key_bytes()),
+                    None,
+                    None,
+                )
+                .unwrap();
+                assert_eq!(
+                    received.get_didcomm_header().thid,
+                    Some(ping.get_didcomm_header().id.to_string())
+                );
+            }
+            _ => panic!("expected response"),
+        }
+        assert_eq!(
+            mediator
+                .connections()
+                .get(did_from.to_string())
+                .await
+                .unwrap()
+                .messages
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_instrumentation() {
+        use crate::instrumentation::tests::CountingInstrumentation;
+        use crate::protocols::forward::ForwardBuilder;
+
+        let instrumentation = Arc::new(CountingInstrumentation::default());
+        let mediator = mediator().instrumentation(instrumentation.clone());
+        let key = generate::<X25519KeyPair>(None);
+        let did_from = key.get_did_document(Default::default()).id;
+
+        for _ in 0..2 {
+            let ping =
+                add_return_route_all_header(TrustPingResponseBuilder::new().build().unwrap());
+            let request = sign_and_encrypt(&ping, &did_from, &mediator.wallet().did_key(), &key)
+                .await
+                .unwrap();
+            mediator
+                .process(&serde_json::to_string(&request).unwrap())
+                .await;
+        }
+        assert_eq!(instrumentation.get("inbound trust-ping/2.0"), 2);
+        assert_eq!(instrumentation.get("cache_miss"), 1);
+        assert_eq!(instrumentation.get("cache_hit"), 1);
+        assert_eq!(instrumentation.get("delivery return-route"), 2);
+
+        let made_up = Message::new().m_type("https://didcomm.org/made-up/9.9/anything");
+        let request = sign_and_encrypt(&made_up, &did_from, &mediator.wallet().did_key(), &key)
+            .await
+            .unwrap();
+        mediator
+            .process(&serde_json::to_string(&request).unwrap())
+            .await;
+        assert_eq!(instrumentation.get("inbound unknown"), 1);
+        assert_eq!(instrumentation.get("inbound made-up/9.9"), 0);
+
+        let forward = ForwardBuilder::new()
+            .did(did_from.to_string())
+            .message("{}".to_string())
+            .build()
+            .unwrap();
+        let request = sign_and_encrypt(&forward, &did_from, &mediator.wallet().did_key(), &key)
+            .await
+            .unwrap();
+        mediator
+            .process(&serde_json::to_string(&request).unwrap())
+            .await;
+        assert_eq!(instrumentation.get("forward_queued"), 1);
+    }
+
+    #[derive(Default)]
+    struct RecordingListener {
+        events: Mutex<Vec<MediatorEvent>>,
+    }
+
+    #[async_trait::async_trait]
+    impl EventListener for RecordingListener {
+        async fn notify(&self, event: &MediatorEvent) {
+            self.events.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        use crate::protocols::basicmessage::BasicMessageBuilder;
+
+        let listener = Arc::new(RecordingListener::default());
+        let mediator = mediator().listener(listener.clone());
+        let key = generate::<X25519KeyPair>(None);
+        let did_from = key.get_did_document(Default::default()).id;
+
+        for message in vec![
+            BasicMessageBuilder::new()
+                .message("hello".to_string())
+                .build()
+                .unwrap(),
+            TrustPingResponseBuilder::new().build().unwrap(),
+        ] {
+            let request = sign_and_encrypt(&message, &did_from, &mediator.wallet().did_key(), &key)
+                .await
+                .unwrap();
+            mediator
+                .process(&serde_json::to_string(&request).unwrap())
+                .await;
+        }
+
+        let events = listener.events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].name(), "basic_message_received");
+        assert_eq!(events[1].name(), "message_queued");
+        assert_eq!(events[1].did(), did_from);
+    }
+
+    #[derive(Default)]
+    struct RecordingNotifier {
+        notified: Mutex<Vec<String>>,
+    }
+
+    #[async_trait::async_trait]
+    impl PushNotifier for RecordingNotifier {
+        async fn notify(&self, did: &str, _device: &crate::connections::DeviceInfo) {
+            self.notified.lock().unwrap().push(did.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push_notifier() {
+        use crate::protocols::pushnotifications::PushNotificationsResponseBuilder;
+
+        let notifier = Arc::new(RecordingNotifier::default());
+        let mediator = mediator().push_notifier(notifier.clone());
+        let key = generate::<X25519KeyPair>(None);
+        let did_from = key.get_did_document(Default::default()).id;
+
+        for message in vec![
+            TrustPingResponseBuilder::new().build().unwrap(),
+            PushNotificationsResponseBuilder::new()
+                .device_token("token".to_string())
+                .build_set_device_info()
+                .unwrap(),
+            TrustPingResponseBuilder::new().build().unwrap(),
+        ] {
+            let request = sign_and_encrypt(&message, &did_from, &mediator.wallet().did_key(), &key)
+                .await
+                .unwrap();
+            mediator
+                .process(&serde_json::to_string(&request).unwrap())
+                .await;
+        }
+        assert_eq!(*notifier.notified.lock().unwrap(), vec![did_from]);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        use crate::config::{RateLimit, RateLimitConfig};
+        use crate::ratelimit::RateLimitInterceptor;
+
+        let mediator =
+            mediator().interceptor(Box::new(RateLimitInterceptor::new(RateLimitConfig {
+                per_did: Some(RateLimit {
+                    capacity: 1,
+                    per_second: 0.0,
+                }),
+                ..Default::default()
+            })));
+        let key = generate::<X25519KeyPair>(None);
+        let did_from = key.get_did_document(Default::default()).id;
+        let ping = TrustPingResponseBuilder::new().build().unwrap();
+        let request = sign_and_encrypt(&ping, &did_from, &mediator.wallet().did_key(), &key)
+            .await
+            .unwrap();
+        let request = serde_json::to_string(&request).unwrap();
+
+        assert_eq!(mediator.process(&request).await, MediatorOutput::Empty);
+        assert_eq!(
+            mediator.process(&request).await,
+            MediatorOutput::TooManyRequests
+        );
+    }
+
+    #[tokio::test]
+    async fn test_message_limits() {
+        use crate::protocols::forward::ForwardBuilder;
+
+        let mediator = mediator().limits(MessageLimits {
+            max_envelope_size: 4096,
+            max_attachment_size: 64,
+            max_attachments: 1,
+            ..Default::default()
+        });
+        let key = generate::<X25519KeyPair>(None);
+        let did_from = key.get_did_document(Default::default()).id;
+        let mediator_did = mediator.wallet().did_key();
+
+        let oversized = "x".repeat(8192);
+        match mediator.process(&oversized).await {
+            MediatorOutput::BadRequest(error) => assert!(error.contains("max_envelope_size")),
+            output => panic!("unexpected {:?}", output),
+        }
+
+        let forward = ForwardBuilder::new()
+            .did(did_from.to_string())
+            .message("x".repeat(128))
+            .build()
+            .unwrap();
+        let request = sign_and_encrypt(&forward, &did_from, &mediator_did, &key)
+            .await
+            .unwrap();
+        match mediator
+            .process(&serde_json::to_string(&request).unwrap())
+            .await
+        {
+            MediatorOutput::BadRequest(error) => assert!(error.contains("max_attachment_size")),
+            output => panic!("unexpected {:?}", output),
+        }
+
+        let forward = ForwardBuilder::new()
+            .did(did_from.to_string())
+            .message("{}".to_string())
+            .message("{}".to_string())
+            .build()
+            .unwrap();
+        let request = sign_and_encrypt(&forward, &did_from, &mediator_did, &key)
+            .await
+            .unwrap();
+        match mediator
+            .process(&serde_json::to_string(&request).unwrap())
+            .await
+        {
+            MediatorOutput::BadRequest(error) => assert!(error.contains("max_attachments")),
+            output => panic!("unexpected {:?}", output),
+        }
+    }
+
+    struct ReplyInterceptor {}
+
+    #[async_trait::async_trait]
+    impl Interceptor for ReplyInterceptor {
+        async fn before_handle(
+            &self,
+            request: &mut Message,
+        ) -> Result<Option<HandlerResponse>, Box<dyn Error>> {
+            let from = request.get_didcomm_header().from.clone().unwrap();
+            let reply = Message::new().body(r#"{"intercepted":true}"#);
+            Ok(Some(HandlerResponse::Reply(from, Box::new(reply))))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_interceptor_short_circuit() {
+        let mediator = mediator().interceptor(Box::new(ReplyInterceptor {}));
+        let key = generate::<X25519KeyPair>(None);
+        let did_from = key.get_did_document(Default::default()).id;
+
+        let ping = add_return_route_all_header(TrustPingResponseBuilder::new().build().unwrap());
+        let request = sign_and_encrypt(&ping, &did_from, &mediator.wallet().did_key(), &key)
+            .await
+            .unwrap();
+        let output = mediator
+            .process(&serde_json::to_string(&request).unwrap())
+            .await;
+        match output {
+            MediatorOutput::Response(response) => {
+                let received = Message::receive(
+                    &serde_json::to_string(&response).unwrap(),
+                    Some(&key.private_key_bytes()),
+                    None,
+                    None,
+                )
+                .unwrap();
+                let body: Value = serde_json::from_str(&received.get_body().unwrap()).unwrap();
+                assert_eq!(body["intercepted"], true);
+            }
+            output => panic!("unexpected {:?}", output),
+        }
+        assert!(mediator.connections().get(did_from).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_live_delivery() {
+        use crate::protocols::forward::ForwardBuilder;
+        use crate::protocols::messagepickup::MessagePickupResponseBuilder;
+        use futures::StreamExt;
+
+        let mediator = mediator();
+        let alice_key = generate::<X25519KeyPair>(None);
+        let alice_did = alice_key.get_did_document(Default::default()).id;
+        let bob_key = generate::<X25519KeyPair>(None);
+        let bob_did = bob_key.get_did_document(Default::default()).id;
+        let mediator_did = mediator.wallet().did_key();
+
+        let live = MessagePickupResponseBuilder::new()
+            .live_delivery(true)
+            .build_live_delivery_change()
+            .unwrap();
+        let live = sign_and_encrypt(&live, &bob_did, &mediator_did, &bob_key)
+            .await
+            .unwrap();
+        let live = serde_json::to_string(&live).unwrap();
+        assert!(matches!(
+            mediator.process(&live).await,
+            MediatorOutput::BadRequest(_)
+        ));
+
+        let (_, mut receiver) = mediator.live().connect(&bob_did);
+        assert_eq!(mediator.process(&live).await, MediatorOutput::Empty);
+
+        let ping = TrustPingResponseBuilder::new().build().unwrap();
+        let ping = sign_and_encrypt(&ping, &alice_did, &bob_did, &alice_key)
+            .await
+            .unwrap();
+        let forward = ForwardBuilder::new()
+            .did(bob_did.to_string())
+            .message_value(ping)
+            .build()
+            .unwrap();
+        let forward = sign_and_encrypt(&forward, &alice_did, &mediator_did, &alice_key)
+            .await
+            .unwrap();
+        assert_eq!(
+            mediator
+                .process(&serde_json::to_string(&forward).unwrap())
+                .await,
+            MediatorOutput::Empty
+        );
+
+        let pushed = receiver.next().await.unwrap();
+        let received = Message::receive(
+            &serde_json::to_string(&pushed).unwrap(),
+            Some(&bob_key.private_key_bytes()),
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            received.get_didcomm_header().m_type,
+            "https://didcomm.org/routing/2.0/forward"
+        );
+        assert!(mediator.connections().get(bob_did).await.is_none());
+    }
+
+    #[test]
+    fn test_resolver_cache() {
+        let mut cache = ResolverCache::default();
+        let now = Utc::now();
+        cache.insert("did:example:0", vec![0], now);
+        assert_eq!(cache.get("did:example:0", now), Some(vec![0]));
+        let expired = now + Duration::seconds(RESOLVER_CACHE_TTL_SECS);
+        assert_eq!(cache.get("did:example:0", expired), None);
+
+        for i in 0..RESOLVER_CACHE_SIZE {
+            cache.insert(&format!("did:example:{}", i), vec![0], now);
+        }
+        let later = now + Duration::seconds(1);
+        assert!(cache.get("did:example:0", later).is_some());
+        cache.insert("did:example:new", vec![1], later);
+        assert_eq!(cache.entries.len(), RESOLVER_CACHE_SIZE);
+        assert!(cache.get("did:example:0", later).is_some());
+        assert!(cache.get("did:example:new", later).is_some());
+    }
+}
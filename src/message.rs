@@ -1,79 +1,184 @@
+use crate::keystore::KeyStore;
 use crate::resolver::resolve;
-use did_key::{generate, DIDCore, Ed25519KeyPair, KeyMaterial, KeyPair};
 use didcomm_rs::Jwe;
-use didcomm_rs::{
-    crypto::{CryptoAlgorithm, SignatureAlgorithm},
-    Message,
-};
-use serde_json::{json, Value};
+use didcomm_rs::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 pub async fn sign_and_encrypt_message(
     request: &Message,
     response: &Message,
-    key: &KeyPair,
+    keys: &dyn KeyStore,
 ) -> Result<Value, Box<dyn std::error::Error>> {
-    let recipient_did = request.get_didcomm_header().from.as_ref().unwrap();
-    let encrypted = sign_and_encrypt(
-        response,
-        &key.get_did_document(Default::default()).id,
-        recipient_did,
-        key,
-    )
-    .await
-    .unwrap();
-    Ok(encrypted)
+    let recipient_did = request
+        .get_didcomm_header()
+        .from
+        .as_ref()
+        .ok_or("from missing")?;
+    sign_and_encrypt(response, &keys.did(), recipient_did, keys).await
 }
 
 pub async fn sign_and_encrypt(
     message: &Message,
     did_from: &str,
     did_to: &str,
-    key: &KeyPair,
+    keys: &dyn KeyStore,
 ) -> Result<Value, Box<dyn std::error::Error>> {
-    let sign_key = generate::<Ed25519KeyPair>(None);
-
-    let recipient_public_key = resolve(did_to).await.unwrap();
-
-    let response = message
-        .clone()
-        .from(did_from)
-        .to(&[did_to])
-        .as_jwe(&CryptoAlgorithm::XC20P, Some(recipient_public_key.to_vec()))
-        .kid(&hex::encode(sign_key.public_key_bytes()));
-
-    let ready_to_send = response
-        .seal_signed(
-            &key.private_key_bytes(),
-            Some(vec![Some(recipient_public_key)]),
-            SignatureAlgorithm::EdDsa,
-            &[sign_key.private_key_bytes(), sign_key.public_key_bytes()].concat(),
+    let recipient_public_key = resolve(did_to)
+        .await
+        .map_err(|error| format!("{:?}", error))?;
+
+    // `did_from` may be another DID of the same key, like the did:web or
+    // did:iota of the mediator, so the current key of the store signs.
+    let kid = keys
+        .key_ids()
+        .first()
+        .cloned()
+        .ok_or("no key in key store")?;
+    let message = message.clone().from(did_from).to(&[did_to]);
+    let ready_to_send = keys.seal(&kid, &message, recipient_public_key).await?;
+    Ok(serde_json::from_str(&ready_to_send)?)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReturnRoute {
+    None,
+    Thread,
+    All,
+}
+
+// https://github.com/decentralized-identity/didcomm-messaging/blob/main/extensions/return_route/main.md
+impl ReturnRoute {
+    pub fn from_message(message: &Message) -> Option<Self> {
+        message
+            .get_application_params()
+            .find(|(key, _)| *key == "return_route")
+            .and_then(|(_, return_route)| {
+                serde_json::from_value(Value::String(return_route.to_string())).ok()
+            })
+    }
+
+    pub fn apply(&self, message: Message) -> Message {
+        let return_route = match self {
+            ReturnRoute::None => "none",
+            ReturnRoute::Thread => "thread",
+            ReturnRoute::All => "all",
+        };
+        message.add_header_field("return_route".to_string(), return_route.to_string())
+    }
+}
+
+// https://github.com/hyperledger/aries-rfcs/tree/main/features/0092-transport-return-route
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Transport {
+    pub return_route: ReturnRoute,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub return_route_thread: Option<String>,
+}
+
+impl Transport {
+    pub fn new(return_route: ReturnRoute) -> Self {
+        Transport {
+            return_route,
+            return_route_thread: None,
+        }
+    }
+
+    pub fn thread(thid: String) -> Self {
+        Transport {
+            return_route: ReturnRoute::Thread,
+            return_route_thread: Some(thid),
+        }
+    }
+
+    pub fn from_message(message: &Message) -> Option<Self> {
+        message
+            .get_application_params()
+            .find(|(key, _)| *key == "~transport")
+            .and_then(|(_, transport)| serde_json::from_str(transport).ok())
+            .or_else(|| ReturnRoute::from_message(message).map(Transport::new))
+    }
+
+    pub fn apply(&self, message: Message) -> Message {
+        message.add_header_field(
+            "~transport".to_string(),
+            serde_json::to_string(self).unwrap(),
         )
-        .unwrap();
-    Ok(serde_json::from_str(&ready_to_send).unwrap())
+    }
+
+    pub fn returns(&self, request: &Message, response: &Message) -> bool {
+        match self.return_route {
+            ReturnRoute::None => false,
+            ReturnRoute::All => true,
+            ReturnRoute::Thread => {
+                let header = request.get_didcomm_header();
+                let thid = self
+                    .return_route_thread
+                    .as_ref()
+                    .or(header.thid.as_ref())
+                    .unwrap_or(&header.id);
+                response.get_didcomm_header().thid.as_ref() == Some(thid)
+            }
+        }
+    }
 }
 
 pub fn add_return_route_all_header(message: Message) -> Message {
-    message.add_header_field(
-        "~transport".to_string(),
-        serde_json::to_string(&json!({
-            "return_route": "all".to_string()
-        }))
-        .unwrap(),
-    )
+    Transport::new(ReturnRoute::All).apply(message)
 }
 
 pub fn has_return_route_all_header(message: &Message) -> bool {
-    match message
-        .get_application_params()
-        .find(|(key, _)| *key == "~transport")
-    {
-        Some((_, transport)) => {
-            serde_json::from_str::<Value>(transport).unwrap()
-                == json!({
-                    "return_route": "all".to_string()
-                })
+    matches!(
+        Transport::from_message(message),
+        Some(Transport {
+            return_route: ReturnRoute::All,
+            ..
+        })
+    )
+}
+
+// https://identity.foundation/didcomm-messaging/spec/#iana-media-types
+// only encrypted envelopes: signed and plaintext ones carry no authenticated sender
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaType {
+    Encrypted,
+    SsiAgentWire,
+    Json,
+}
+
+impl MediaType {
+    pub fn parse(content_type: &str) -> Option<Self> {
+        let essence = content_type.split(';').next()?.trim().to_ascii_lowercase();
+        match essence.as_str() {
+            "application/didcomm-encrypted+json" => Some(MediaType::Encrypted),
+            "application/ssi-agent-wire" => Some(MediaType::SsiAgentWire),
+            "application/json" => Some(MediaType::Json),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaType::Encrypted => "application/didcomm-encrypted+json",
+            MediaType::SsiAgentWire => "application/ssi-agent-wire",
+            MediaType::Json => "application/json",
+        }
+    }
+
+    /// A request without a `Content-Type` is a legacy JSON envelope.
+    pub fn from_header(content_type: Option<&str>) -> Option<Self> {
+        match content_type {
+            Some(content_type) => Self::parse(content_type),
+            None => Some(MediaType::Json),
+        }
+    }
+
+    pub fn response_type(&self) -> MediaType {
+        match self {
+            MediaType::SsiAgentWire => MediaType::SsiAgentWire,
+            _ => MediaType::Encrypted,
         }
-        _ => false,
     }
 }
 
@@ -105,7 +210,7 @@ pub async fn receive(
 mod tests {
     use super::*;
     use base58::FromBase58;
-    use did_key::X25519KeyPair;
+    use did_key::{generate, KeyMaterial, X25519KeyPair};
 
     #[tokio::test]
     async fn test_encrypt_message() {
@@ -139,6 +244,29 @@ mod tests {
         assert_eq!(sample_body.to_string(), received_body.to_string(),);
     }
 
+    #[test]
+    fn test_media_type() {
+        assert_eq!(
+            MediaType::parse("application/didcomm-encrypted+json"),
+            Some(MediaType::Encrypted)
+        );
+        assert_eq!(
+            MediaType::parse("Application/SSI-Agent-Wire; charset=utf-8"),
+            Some(MediaType::SsiAgentWire)
+        );
+        assert_eq!(MediaType::parse("text/plain"), None);
+        assert_eq!(MediaType::parse("application/didcomm-plain+json"), None);
+        assert_eq!(MediaType::from_header(None), Some(MediaType::Json));
+        assert_eq!(
+            MediaType::Json.response_type().as_str(),
+            "application/didcomm-encrypted+json"
+        );
+        assert_eq!(
+            MediaType::SsiAgentWire.response_type(),
+            MediaType::SsiAgentWire
+        );
+    }
+
     #[test]
     fn test_return_route_all() {
         let mut message = Message::new();
@@ -147,6 +275,56 @@ mod tests {
         assert!(has_return_route_all_header(&message));
     }
 
+    #[test]
+    fn test_return_route_v2() {
+        let message = ReturnRoute::All.apply(Message::new());
+        assert_eq!(ReturnRoute::from_message(&message), Some(ReturnRoute::All));
+        assert!(has_return_route_all_header(&message));
+
+        let message: Message = serde_json::from_value({
+            let mut value = serde_json::to_value(Message::new()).unwrap();
+            value["return_route"] = serde_json::json!("thread");
+            value
+        })
+        .unwrap();
+        assert_eq!(
+            Transport::from_message(&message),
+            Some(Transport::new(ReturnRoute::Thread))
+        );
+        assert!(!has_return_route_all_header(&message));
+    }
+
+    #[test]
+    fn test_transport() {
+        let message = Message::new();
+        assert_eq!(Transport::from_message(&message), None);
+
+        let message = Transport::new(ReturnRoute::None).apply(Message::new());
+        assert_eq!(
+            Transport::from_message(&message),
+            Some(Transport::new(ReturnRoute::None))
+        );
+        assert!(!has_return_route_all_header(&message));
+
+        let message = Transport::thread("42".to_string()).apply(Message::new());
+        let transport = Transport::from_message(&message).unwrap();
+        assert_eq!(transport.return_route, ReturnRoute::Thread);
+        assert_eq!(transport.return_route_thread, Some("42".to_string()));
+    }
+
+    #[test]
+    fn test_transport_returns() {
+        let request = Message::new().thid("42");
+        let on_thread = Message::new().thid("42");
+        let off_thread = Message::new().thid("43");
+
+        assert!(Transport::new(ReturnRoute::All).returns(&request, &off_thread));
+        assert!(!Transport::new(ReturnRoute::None).returns(&request, &on_thread));
+        assert!(Transport::new(ReturnRoute::Thread).returns(&request, &on_thread));
+        assert!(!Transport::new(ReturnRoute::Thread).returns(&request, &off_thread));
+        assert!(Transport::thread("43".to_string()).returns(&request, &off_thread));
+    }
+
     #[cfg(feature = "iota")]
     #[tokio::test]
     async fn test_iota_message_encryption() -> Result<(), Box<dyn std::error::Error>> {
@@ -0,0 +1,171 @@
+use crate::instrumentation::Instrumentation;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::time::Duration;
+
+pub struct PrometheusMetrics {
+    registry: Registry,
+    inbound: IntCounterVec,
+    decrypt_failures: IntCounter,
+    forwards_queued: IntCounter,
+    pickups_served: IntCounter,
+    queue_depth: IntGauge,
+    resolver_duration: Histogram,
+    resolver_cache_hits: IntCounter,
+    delivery_attempts: IntCounterVec,
+}
+
+impl Default for PrometheusMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PrometheusMetrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let inbound = IntCounterVec::new(
+            Opts::new(
+                "didcomm_inbound_messages_total",
+                "Inbound messages by protocol",
+            ),
+            &["protocol"],
+        )
+        .unwrap();
+        let decrypt_failures = IntCounter::new(
+            "didcomm_decrypt_failures_total",
+            "Inbound messages that failed to decrypt",
+        )
+        .unwrap();
+        let forwards_queued = IntCounter::new(
+            "didcomm_forwards_queued_total",
+            "Forwarded messages queued for pickup",
+        )
+        .unwrap();
+        let pickups_served = IntCounter::new(
+            "didcomm_pickups_served_total",
+            "Messages handed out by pickup",
+        )
+        .unwrap();
+        let queue_depth = IntGauge::new(
+            "didcomm_queue_depth",
+            "Messages queued for pickup across all connections",
+        )
+        .unwrap();
+        let resolver_duration = Histogram::with_opts(HistogramOpts::new(
+            "didcomm_resolver_duration_seconds",
+            "Sender key resolution latency",
+        ))
+        .unwrap();
+        let resolver_cache_hits = IntCounter::new(
+            "didcomm_resolver_cache_hits_total",
+            "Sender key resolutions served from cache",
+        )
+        .unwrap();
+        let delivery_attempts = IntCounterVec::new(
+            Opts::new(
+                "didcomm_outbound_delivery_attempts_total",
+                "Outbound delivery attempts by transport and outcome",
+            ),
+            &["transport", "outcome"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(inbound.clone())).unwrap();
+        registry
+            .register(Box::new(decrypt_failures.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(forwards_queued.clone()))
+            .unwrap();
+        registry.register(Box::new(pickups_served.clone())).unwrap();
+        registry.register(Box::new(queue_depth.clone())).unwrap();
+        registry
+            .register(Box::new(resolver_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(resolver_cache_hits.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(delivery_attempts.clone()))
+            .unwrap();
+
+        PrometheusMetrics {
+            registry,
+            inbound,
+            decrypt_failures,
+            forwards_queued,
+            pickups_served,
+            queue_depth,
+            resolver_duration,
+            resolver_cache_hits,
+            delivery_attempts,
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+impl Instrumentation for PrometheusMetrics {
+    fn inbound(&self, protocol: &str) {
+        self.inbound.with_label_values(&[protocol]).inc();
+    }
+
+    fn decrypt_failure(&self) {
+        self.decrypt_failures.inc();
+    }
+
+    fn forward_queued(&self) {
+        self.forwards_queued.inc();
+    }
+
+    fn pickups_served(&self, count: usize) {
+        self.pickups_served.inc_by(count as u64);
+    }
+
+    fn queue_depth(&self, depth: usize) {
+        self.queue_depth.set(depth as i64);
+    }
+
+    fn resolved(&self, duration: Duration, cache_hit: bool) {
+        self.resolver_duration.observe(duration.as_secs_f64());
+        if cache_hit {
+            self.resolver_cache_hits.inc();
+        }
+    }
+
+    fn delivery_attempt(&self, transport: &str, delivered: bool) {
+        let outcome = if delivered { "delivered" } else { "failed" };
+        self.delivery_attempts
+            .with_label_values(&[transport, outcome])
+            .inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = PrometheusMetrics::new();
+        metrics.inbound("trust-ping/2.0");
+        metrics.delivery_attempt("live", false);
+        metrics.queue_depth(3);
+        metrics.queue_depth(1);
+        let rendered = metrics.render();
+        assert!(rendered.contains("didcomm_queue_depth 1"));
+        assert!(rendered.contains("didcomm_inbound_messages_total{protocol=\"trust-ping/2.0\"} 1"));
+        assert!(rendered.contains(
+            "didcomm_outbound_delivery_attempts_total{outcome=\"failed\",transport=\"live\"} 1"
+        ));
+    }
+}
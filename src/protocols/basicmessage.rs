@@ -2,9 +2,8 @@
 
 use crate::connections::ConnectionStorage;
 use crate::handler::{DidcommHandler, HandlerResponse};
-use async_mutex::Mutex;
+use crate::keystore::KeyStore;
 use async_trait::async_trait;
-use did_key::KeyPair;
 use didcomm_rs::Message;
 use serde_json::json;
 use std::error::Error;
@@ -49,8 +48,8 @@ impl DidcommHandler for BasicMessageHandler {
     async fn handle(
         &self,
         request: &Message,
-        _key: Option<&KeyPair>,
-        _connections: Option<&Arc<Mutex<Box<dyn ConnectionStorage>>>>,
+        _keys: Option<&dyn KeyStore>,
+        _connections: Option<&Arc<dyn ConnectionStorage>>,
     ) -> Result<HandlerResponse, Box<dyn Error>> {
         if request
             .get_didcomm_header()
@@ -1,14 +1,15 @@
 // https://github.com/hyperledger/aries-rfcs/blob/main/features/0023-did-exchange/README.md
 use crate::connections::ConnectionStorage;
 use crate::handler::{DidcommHandler, HandlerResponse};
-use async_mutex::Mutex;
+use crate::keystore::KeyStore;
+use crate::message::{ReturnRoute, Transport};
 use async_trait::async_trait;
-use did_key::KeyPair;
 use did_key::{DIDCore, CONFIG_LD_PUBLIC};
 use didcomm_rs::Message;
 use serde_json::Value;
 use std::error::Error;
 use std::sync::Arc;
+use tracing::warn;
 use uuid::Uuid;
 
 #[derive(Default)]
@@ -16,6 +17,8 @@ pub struct DidExchangeResponseBuilder {
     did: Option<String>,
     message: Option<Message>,
     did_doc: Option<Value>,
+    return_route: Option<ReturnRoute>,
+    transport: Option<Transport>,
 }
 
 impl DidExchangeResponseBuilder {
@@ -38,6 +41,16 @@ impl DidExchangeResponseBuilder {
         self
     }
 
+    pub fn return_route(&mut self, return_route: ReturnRoute) -> &mut Self {
+        self.return_route = Some(return_route);
+        self
+    }
+
+    pub fn transport(&mut self, transport: Transport) -> &mut Self {
+        self.transport = Some(transport);
+        self
+    }
+
     pub fn build(&mut self) -> Result<Message, &'static str> {
         match &self.message {
             Some(message) => match message.get_didcomm_header().m_type.as_str() {
@@ -45,8 +58,8 @@ impl DidExchangeResponseBuilder {
                 "https://didcomm.org/out-of-band/2.0/invitation" => self.build_request(),
                 "https://didcomm.org/didexchange/1.0/request" => self.build_response(),
                 "https://didcomm.org/didexchange/1.0/response" => self.build_complete(),
-                _ => {
-                    println!("{}", message.get_didcomm_header().m_type.as_str());
+                m_type => {
+                    warn!(m_type, "unsupported did exchange message");
                     Err("unsupported message")
                 }
             },
@@ -59,7 +72,7 @@ impl DidExchangeResponseBuilder {
             Some(message) => message.get_didcomm_header().thid.clone().unwrap(),
             _ => Uuid::new_v4().to_string(),
         };
-        Ok(Message::new()
+        let mut message = Message::new()
             .m_type("https://didcomm.org/didexchange/1.0/request")
             .thid(&thid)
             .pthid(&thid)
@@ -68,7 +81,14 @@ impl DidExchangeResponseBuilder {
             .add_header_field(
                 "did_doc~attach".to_string(),
                 serde_json::to_string_pretty(&self.did_doc.clone().unwrap()).unwrap(),
-            ))
+            );
+        if let Some(return_route) = &self.return_route {
+            message = return_route.apply(message);
+        }
+        if let Some(transport) = &self.transport {
+            message = transport.apply(message);
+        }
+        Ok(message)
     }
 
     pub fn build_response(&mut self) -> Result<Message, &'static str> {
@@ -108,8 +128,8 @@ impl DidcommHandler for DidExchangeHandler {
     async fn handle(
         &self,
         request: &Message,
-        key: Option<&KeyPair>,
-        _connections: Option<&Arc<Mutex<Box<dyn ConnectionStorage>>>>,
+        keys: Option<&dyn KeyStore>,
+        _connections: Option<&Arc<dyn ConnectionStorage>>,
     ) -> Result<HandlerResponse, Box<dyn Error>> {
         if request
             .get_didcomm_header()
@@ -122,9 +142,10 @@ impl DidcommHandler for DidExchangeHandler {
             .m_type
             .starts_with("https://didcomm.org/didexchange/1.0")
         {
-            let did = key.unwrap().get_did_document(CONFIG_LD_PUBLIC).id;
-            let mut did_doc = key.unwrap().get_did_document(CONFIG_LD_PUBLIC);
-            did_doc.verification_method[0].private_key = None;
+            let did = keys.unwrap().did();
+            let did_doc = did_key::resolve(&did)
+                .map_err(|_| "could not resolve mediator did")?
+                .get_did_document(CONFIG_LD_PUBLIC);
             let did_to = request.get_didcomm_header().from.clone().unwrap();
             let response = DidExchangeResponseBuilder::new()
                 .message(request.clone())
@@ -2,10 +2,8 @@
 
 use crate::connections::ConnectionStorage;
 use crate::handler::{DidcommHandler, HandlerResponse};
-use crate::message::sign_and_encrypt_message;
-use async_mutex::Mutex;
+use crate::keystore::KeyStore;
 use async_trait::async_trait;
-use did_key::KeyPair;
 use didcomm_rs::Message;
 use serde_json::json;
 use std::error::Error;
@@ -85,23 +83,24 @@ impl DidcommHandler for DiscoverFeaturesHandler {
     async fn handle(
         &self,
         request: &Message,
-        key: Option<&KeyPair>,
-        _connections: Option<&Arc<Mutex<Box<dyn ConnectionStorage>>>>,
+        _keys: Option<&dyn KeyStore>,
+        _connections: Option<&Arc<dyn ConnectionStorage>>,
     ) -> Result<HandlerResponse, Box<dyn Error>> {
         if request
             .get_didcomm_header()
             .m_type
             .starts_with("https://didcomm.org/discover-features/2.0")
         {
+            let did_from = request
+                .get_didcomm_header()
+                .from
+                .clone()
+                .ok_or("from missing")?;
             let response = DiscoverFeaturesResponseBuilder::new()
                 .message(request.clone())
-                .build()
-                .unwrap();
-            let response = sign_and_encrypt_message(request, &response, key.unwrap());
+                .build()?;
 
-            Ok(HandlerResponse::Response(
-                serde_json::to_value(&response.await.unwrap()).unwrap(),
-            ))
+            Ok(HandlerResponse::Reply(did_from, Box::new(response)))
         } else {
             Ok(HandlerResponse::Skipped)
         }
@@ -1,25 +1,54 @@
 // https://identity.foundation/didcomm-messaging/spec/#messages
 use crate::connections::ConnectionStorage;
 use crate::handler::{DidcommHandler, HandlerResponse};
-use async_mutex::Mutex;
+use crate::keystore::KeyStore;
 use async_trait::async_trait;
-use did_key::KeyPair;
-use didcomm_rs::{AttachmentBuilder, AttachmentDataBuilder, Message};
+use didcomm_rs::{Attachment, AttachmentBuilder, AttachmentDataBuilder, Message};
 use serde_json::{json, Value};
 use std::error::Error;
 use std::sync::Arc;
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum ForwardAttachment {
+    Json(String),
+    Base64(String),
+}
+
+impl ForwardAttachment {
+    pub fn from_attachment(attachment: &Attachment) -> Option<Self> {
+        match (&attachment.data.json, &attachment.data.base64) {
+            (Some(json), _) => Some(ForwardAttachment::Json(json.to_string())),
+            (None, Some(base64)) => Some(ForwardAttachment::Base64(base64.to_string())),
+            _ => None,
+        }
+    }
+
+    fn to_builder(&self) -> AttachmentBuilder {
+        let data = match self {
+            ForwardAttachment::Json(json) => {
+                AttachmentDataBuilder::new().with_link("").with_json(json)
+            }
+            ForwardAttachment::Base64(base64) => AttachmentDataBuilder::new()
+                .with_link("")
+                .with_encoded_payload(base64),
+        };
+        AttachmentBuilder::new(true).with_data(data)
+    }
+}
+
 #[derive(Default)]
 pub struct ForwardBuilder {
     did: Option<String>,
-    message: Option<String>,
+    attachments: Vec<ForwardAttachment>,
+    expires_time: Option<u64>,
 }
 
 impl ForwardBuilder {
     pub fn new() -> Self {
         ForwardBuilder {
             did: None,
-            message: None,
+            attachments: Vec::new(),
+            expires_time: None,
         }
     }
 
@@ -29,22 +58,50 @@ impl ForwardBuilder {
     }
 
     pub fn message(&mut self, message: String) -> &mut Self {
-        self.message = Some(message);
+        self.attachments.push(ForwardAttachment::Json(message));
+        self
+    }
+
+    pub fn message_value(&mut self, message: Value) -> &mut Self {
+        self.attachments
+            .push(ForwardAttachment::Json(message.to_string()));
+        self
+    }
+
+    pub fn base64(&mut self, payload: String) -> &mut Self {
+        self.attachments.push(ForwardAttachment::Base64(payload));
+        self
+    }
+
+    pub fn attachments(&mut self, attachments: Vec<ForwardAttachment>) -> &mut Self {
+        self.attachments.extend(attachments);
+        self
+    }
+
+    pub fn expires_time(&mut self, expires_time: u64) -> &mut Self {
+        self.expires_time = Some(expires_time);
         self
     }
 
     pub fn build(&mut self) -> Result<Message, &'static str> {
+        let did = self.did.as_ref().ok_or("no did")?;
+        if self.attachments.is_empty() {
+            return Err("no message");
+        }
         let mut message = Message::new()
             .m_type("https://didcomm.org/routing/2.0/forward")
-            .body(&json!({"next": self.did.as_ref().unwrap()}).to_string());
-        message.append_attachment(
-            AttachmentBuilder::new(true).with_data(
-                AttachmentDataBuilder::new()
-                    .with_link("")
-                    .with_json(self.message.as_ref().unwrap()),
-            ),
-        );
-        Ok(message)
+            .body(&json!({ "next": did }).to_string());
+        for attachment in &self.attachments {
+            message.append_attachment(attachment.to_builder());
+        }
+        match self.expires_time {
+            Some(expires_time) => {
+                let mut value = serde_json::to_value(&message).map_err(|_| "invalid message")?;
+                value["expires_time"] = json!(expires_time);
+                serde_json::from_value(value).map_err(|_| "invalid message")
+            }
+            None => Ok(message),
+        }
     }
 }
 
@@ -56,26 +113,36 @@ impl DidcommHandler for ForwardHandler {
     async fn handle(
         &self,
         request: &Message,
-        _key: Option<&KeyPair>,
-        _connections: Option<&Arc<Mutex<Box<dyn ConnectionStorage>>>>,
+        _keys: Option<&dyn KeyStore>,
+        _connections: Option<&Arc<dyn ConnectionStorage>>,
     ) -> Result<HandlerResponse, Box<dyn Error>> {
         if request
             .get_didcomm_header()
             .m_type
             .starts_with("https://didcomm.org/routing/2.0/forward")
         {
-            match request.get_attachments().next() {
-                Some(attachment) => {
-                    let body: Value = serde_json::from_str(&request.get_body().unwrap()).unwrap();
-                    let did_to = body["next"].as_str().unwrap();
-                    let response_json = attachment.data.json.as_ref().unwrap();
-                    Ok(HandlerResponse::Forward(
-                        vec![did_to.to_string()],
-                        serde_json::from_str(response_json).unwrap(),
-                    ))
-                }
-                _ => Ok(HandlerResponse::Processed),
+            let attachments = request
+                .get_attachments()
+                .map(|attachment| {
+                    ForwardAttachment::from_attachment(attachment)
+                        .ok_or("forward attachment without json or base64 data")
+                })
+                .collect::<Result<Vec<ForwardAttachment>, _>>()?;
+            if attachments.is_empty() {
+                return Ok(HandlerResponse::Processed);
+            }
+            let body: Value = serde_json::from_str(&request.get_body()?)?;
+            let did_to = body["next"].as_str().ok_or("next missing")?;
+
+            let mut builder = ForwardBuilder::new();
+            builder.did(did_to.to_string()).attachments(attachments);
+            if let Some(expires_time) = request.get_didcomm_header().expires_time {
+                builder.expires_time(expires_time);
             }
+            Ok(HandlerResponse::Forward(
+                vec![did_to.to_string()],
+                Box::new(builder.build()?),
+            ))
         } else {
             Ok(HandlerResponse::Skipped)
         }
@@ -103,4 +170,72 @@ mod tests {
 
         println!("{}", serde_json::to_string_pretty(&response).unwrap());
     }
+
+    #[test]
+    fn test_build_forward_attachments() {
+        let response = ForwardBuilder::new()
+            .did("did:test".to_string())
+            .message_value(json!({"foo": "bar"}))
+            .base64("eyJmb28iOiJiYXIifQ".to_string())
+            .expires_time(42)
+            .build()
+            .unwrap();
+
+        let attachments: Vec<ForwardAttachment> = response
+            .get_attachments()
+            .filter_map(ForwardAttachment::from_attachment)
+            .collect();
+        assert_eq!(
+            attachments,
+            vec![
+                ForwardAttachment::Json(json!({"foo": "bar"}).to_string()),
+                ForwardAttachment::Base64("eyJmb28iOiJiYXIifQ".to_string())
+            ]
+        );
+        assert_eq!(response.get_didcomm_header().expires_time, Some(42));
+    }
+
+    #[test]
+    fn test_build_forward_without_message() {
+        assert!(ForwardBuilder::new()
+            .did("did:test".to_string())
+            .build()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handler() {
+        let request = ForwardBuilder::new()
+            .did("did:test".to_string())
+            .message("{}".to_string())
+            .base64("e30".to_string())
+            .expires_time(42)
+            .build()
+            .unwrap();
+
+        let handler = ForwardHandler::default();
+        let response = handler.handle(&request, None, None).await.unwrap();
+        match response {
+            HandlerResponse::Forward(receivers, message) => {
+                assert_eq!(receivers, vec!["did:test".to_string()]);
+                assert_eq!(message.get_attachments().count(), 2);
+                assert_eq!(message.get_didcomm_header().expires_time, Some(42));
+            }
+            _ => panic!("expected forward"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handler_link_attachment() {
+        let mut request = Message::new()
+            .m_type("https://didcomm.org/routing/2.0/forward")
+            .body(&json!({ "next": "did:test" }).to_string());
+        request.append_attachment(
+            AttachmentBuilder::new(true)
+                .with_data(AttachmentDataBuilder::new().with_link("https://example.com/payload")),
+        );
+
+        let handler = ForwardHandler::default();
+        assert!(handler.handle(&request, None, None).await.is_err());
+    }
 }
@@ -1,22 +1,26 @@
 // https://github.com/hyperledger/aries-rfcs/tree/main/features/0212-pickup
+// https://didcomm.org/pickup/2.0/
+use crate::config::MessageLimits;
 use crate::connections::ConnectionStorage;
 use crate::handler::{DidcommHandler, HandlerResponse};
-use crate::message::sign_and_encrypt_message;
-use async_mutex::Mutex;
+use crate::keystore::KeyStore;
 use async_trait::async_trait;
-use did_key::KeyPair;
-use did_key::{DIDCore, CONFIG_LD_PUBLIC};
 use didcomm_rs::{AttachmentBuilder, AttachmentDataBuilder, Message};
+use serde_json::{json, Value};
 use std::error::Error;
 use std::sync::Arc;
 use uuid::Uuid;
 
+pub const BATCH: &str = "https://didcomm.org/messagepickup/1.0/batch";
+
 #[derive(Default)]
 pub struct MessagePickupResponseBuilder<'a> {
     did: Option<String>,
     message: Option<Message>,
-    connections: Option<&'a Arc<Mutex<Box<dyn ConnectionStorage>>>>,
+    connections: Option<&'a Arc<dyn ConnectionStorage>>,
     batch_size: Option<u32>,
+    max_batch_size: Option<usize>,
+    live_delivery: Option<bool>,
 }
 
 impl<'a> MessagePickupResponseBuilder<'a> {
@@ -26,6 +30,8 @@ impl<'a> MessagePickupResponseBuilder<'a> {
             message: None,
             connections: None,
             batch_size: None,
+            max_batch_size: None,
+            live_delivery: None,
         }
     }
 
@@ -39,15 +45,23 @@ impl<'a> MessagePickupResponseBuilder<'a> {
         self
     }
 
+    /// Caps the `batch_size` a `batch-pickup` asks for.
+    pub fn max_batch_size(&mut self, max_batch_size: usize) -> &mut Self {
+        self.max_batch_size = Some(max_batch_size);
+        self
+    }
+
+    pub fn live_delivery(&mut self, live_delivery: bool) -> &mut Self {
+        self.live_delivery = Some(live_delivery);
+        self
+    }
+
     pub fn message(&mut self, message: Message) -> &mut Self {
         self.message = Some(message);
         self
     }
 
-    pub fn connections(
-        &mut self,
-        connections: &'a Arc<Mutex<Box<dyn ConnectionStorage>>>,
-    ) -> &mut Self {
+    pub fn connections(&mut self, connections: &'a Arc<dyn ConnectionStorage>) -> &mut Self {
         self.connections = Some(connections);
         self
     }
@@ -69,10 +83,11 @@ impl<'a> MessagePickupResponseBuilder<'a> {
 
     async fn build_status(&mut self) -> Result<Message, &'static str> {
         let message: Message = {
-            let connection = {
-                let muted = self.connections.unwrap().try_lock().unwrap();
-                muted.get(self.did.as_ref().unwrap().to_string()).await
-            };
+            let connection = self
+                .connections
+                .unwrap()
+                .get(self.did.as_ref().unwrap().to_string())
+                .await;
             match connection {
                 Some(connection) => Message::new().add_header_field(
                     "message_count".to_string(),
@@ -87,6 +102,12 @@ impl<'a> MessagePickupResponseBuilder<'a> {
             .thid(&self.message.as_ref().unwrap().get_didcomm_header().id))
     }
 
+    pub fn build_live_delivery_change(&mut self) -> Result<Message, &'static str> {
+        Ok(Message::new()
+            .m_type("https://didcomm.org/messagepickup/2.0/live-delivery-change")
+            .body(&json!({ "live_delivery": self.live_delivery.unwrap_or(true) }).to_string()))
+    }
+
     pub fn build_batch_pickup(&mut self) -> Result<Message, &'static str> {
         Ok(Message::new()
             .m_type("https://didcomm.org/messagepickup/1.0/batch-pickup")
@@ -111,88 +132,110 @@ impl<'a> MessagePickupResponseBuilder<'a> {
             .unwrap()
             .get_application_params()
             .find(|(key, _)| *key == "batch_size")
-            .unwrap();
-        let batch_size = batch_size.clone().parse::<usize>().unwrap();
+            .ok_or("batch_size missing")?;
+        let batch_size = batch_size
+            .parse::<usize>()
+            .map_err(|_| "invalid batch_size")?
+            .min(self.max_batch_size.unwrap_or(usize::MAX));
+
+        let messages = self
+            .connections
+            .unwrap()
+            .get_messages(did_from, batch_size)
+            .await;
 
-        let messages = {
-            let mut connections = self.connections.unwrap().try_lock().unwrap();
-            let messages = connections.get_messages(did_from, batch_size).await;
-            messages
-        };
+        Ok(batch_message(messages.unwrap_or_default())
+            .thid(&self.message.as_ref().unwrap().get_didcomm_header().id))
+    }
+}
 
-        let batch: Message = match messages {
-            Some(messages) => {
-                let attachments: Vec<AttachmentBuilder> = messages
-                    .into_iter()
-                    .map(|message| {
-                        AttachmentBuilder::new(true)
-                            .with_id(&Uuid::new_v4().to_string())
-                            .with_data(
-                                AttachmentDataBuilder::new()
-                                    .with_link("no")
-                                    .with_json(&serde_json::to_string(&message).unwrap()),
-                            )
-                    })
-                    .collect();
-
-                let mut message = Message::new();
-                for attachment in attachments {
-                    message.append_attachment(attachment);
-                }
+pub fn batch_message(messages: Vec<Message>) -> Message {
+    let mut batch = Message::new().m_type(BATCH);
+    for message in messages {
+        batch.append_attachment(
+            AttachmentBuilder::new(true)
+                .with_id(&Uuid::new_v4().to_string())
+                .with_data(
+                    AttachmentDataBuilder::new()
+                        .with_link("no")
+                        .with_json(&serde_json::to_string(&message).unwrap()),
+                ),
+        );
+    }
+    batch
+}
 
-                message
-            }
-            _ => Message::new(),
-        };
+/// The messages of a `batch`, in order.
+pub fn batch_messages(batch: &Message) -> Vec<Message> {
+    batch
+        .get_attachments()
+        .filter_map(|attachment| attachment.data.json.as_ref())
+        .filter_map(|json| serde_json::from_str(json).ok())
+        .collect()
+}
 
-        Ok(batch
-            .m_type("https://didcomm.org/messagepickup/1.0/batch")
-            .thid(&self.message.as_ref().unwrap().get_didcomm_header().id))
+pub struct MessagePickupHandler {
+    max_batch_size: usize,
+}
+
+impl MessagePickupHandler {
+    pub fn new(max_batch_size: usize) -> Self {
+        MessagePickupHandler { max_batch_size }
     }
 }
 
-#[derive(Default)]
-pub struct MessagePickupHandler {}
+impl Default for MessagePickupHandler {
+    fn default() -> Self {
+        MessagePickupHandler::new(MessageLimits::default().max_batch_size)
+    }
+}
 
 #[async_trait]
 impl DidcommHandler for MessagePickupHandler {
     async fn handle(
         &self,
         request: &Message,
-        key: Option<&KeyPair>,
-        connections: Option<&Arc<Mutex<Box<dyn ConnectionStorage>>>>,
+        keys: Option<&dyn KeyStore>,
+        connections: Option<&Arc<dyn ConnectionStorage>>,
     ) -> Result<HandlerResponse, Box<dyn Error>> {
-        let key = key.unwrap();
-        let did = key.get_did_document(CONFIG_LD_PUBLIC).id;
+        if request
+            .get_didcomm_header()
+            .m_type
+            .eq("https://didcomm.org/messagepickup/2.0/live-delivery-change")
+        {
+            let body: Value = serde_json::from_str(&request.get_body()?)?;
+            let did_from = request
+                .get_didcomm_header()
+                .from
+                .clone()
+                .ok_or("from missing")?;
+            return Ok(HandlerResponse::LiveDelivery(
+                did_from,
+                body["live_delivery"].as_bool().unwrap_or(false),
+            ));
+        }
+        let did = keys.unwrap().did();
         match request
             .get_didcomm_header()
             .m_type
             .starts_with("https://didcomm.org/messagepickup/1.0/")
         {
             true => {
-                let response = {
-                    let connections: Arc<Mutex<Box<dyn ConnectionStorage>>> =
-                        connections.unwrap().clone();
-                    let message = MessagePickupResponseBuilder::new()
-                        .message(request.clone())
-                        .did(did)
-                        .connections(&connections)
-                        .build()
-                        .await;
-                    drop(connections);
-                    message
-                };
+                let did_from = request
+                    .get_didcomm_header()
+                    .from
+                    .clone()
+                    .ok_or("from missing")?;
+                let response = MessagePickupResponseBuilder::new()
+                    .message(request.clone())
+                    .did(did)
+                    .max_batch_size(self.max_batch_size)
+                    .connections(connections.unwrap())
+                    .build()
+                    .await;
 
                 match response {
-                    Ok(response) => {
-                        let response = match sign_and_encrypt_message(request, &response, key).await
-                        {
-                            Ok(response) => response,
-                            Err(error) => serde_json::to_value(error.to_string()).unwrap(),
-                        };
-
-                        Ok(HandlerResponse::Response(response))
-                    }
+                    Ok(response) => Ok(HandlerResponse::Reply(did_from, Box::new(response))),
                     Err(_) => Ok(HandlerResponse::Processed),
                 }
             }
@@ -205,7 +248,7 @@ impl DidcommHandler for MessagePickupHandler {
 mod tests {
     use super::*;
     use crate::connections::Connections;
-    use did_key::{generate, X25519KeyPair};
+    use did_key::{generate, DIDCore, X25519KeyPair};
 
     #[tokio::test]
     async fn test_build_status_request() {
@@ -235,12 +278,13 @@ mod tests {
             "https://didcomm.org/messagepickup/1.0/status-request"
         );
 
-        let mut connections = Connections::default();
+        let connections = Connections::default();
         let message = Message::new().to(&["did:test"]);
         connections.insert_message(message).await;
 
+        let connections: Arc<dyn ConnectionStorage> = Arc::new(connections);
         let response = MessagePickupResponseBuilder::new()
-            .connections(&Arc::new(Mutex::new(Box::new(connections))))
+            .connections(&connections)
             .message(request)
             .did("did:test".to_string())
             .build()
@@ -293,7 +337,7 @@ mod tests {
             "https://didcomm.org/messagepickup/1.0/batch-pickup"
         );
 
-        let mut connections = Connections::default();
+        let connections = Connections::default();
         let message1 = Message::new().to(&["did:test"]);
         connections.insert_message(message1).await;
         let message2 = Message::new().to(&["did:test"]);
@@ -309,8 +353,7 @@ mod tests {
             2
         );
 
-        let connections: Arc<Mutex<Box<dyn ConnectionStorage>>> =
-            Arc::new(Mutex::new(Box::new(connections)));
+        let connections: Arc<dyn ConnectionStorage> = Arc::new(connections);
 
         let response = MessagePickupResponseBuilder::new()
             .connections(&connections)
@@ -325,12 +368,10 @@ mod tests {
             "https://didcomm.org/messagepickup/1.0/batch"
         );
 
-        assert!(response.get_attachments().next().is_some());
+        assert_eq!(batch_messages(&response).len(), 1);
 
         assert_eq!(
             connections
-                .try_lock()
-                .unwrap()
                 .get("did:test".to_string())
                 .await
                 .unwrap()
@@ -360,8 +401,7 @@ mod tests {
 
         assert!(connections.get("did:test".to_string()).await.is_none());
 
-        let connections: Arc<Mutex<Box<dyn ConnectionStorage>>> =
-            Arc::new(Mutex::new(Box::new(connections)));
+        let connections: Arc<dyn ConnectionStorage> = Arc::new(connections);
 
         let response = MessagePickupResponseBuilder::new()
             .connections(&connections)
@@ -378,12 +418,7 @@ mod tests {
 
         assert!(response.get_attachments().next().is_none());
 
-        assert!(connections
-            .try_lock()
-            .unwrap()
-            .get("did:test".to_string())
-            .await
-            .is_none());
+        assert!(connections.get("did:test".to_string()).await.is_none());
 
         println!("{}", serde_json::to_string_pretty(&response).unwrap());
     }
@@ -403,13 +438,30 @@ mod tests {
             "https://didcomm.org/messagepickup/1.0/status-request"
         );
         let handler = MessagePickupHandler::default();
+        let connections: Arc<dyn ConnectionStorage> = Arc::new(Connections::default());
         let response = handler
-            .handle(
-                &request,
-                Some(&key),
-                Some(&Arc::new(Mutex::new(Box::new(Connections::default())))),
-            )
+            .handle(&request, Some(&key), Some(&connections))
             .await;
         assert_ne!(response.unwrap(), HandlerResponse::Skipped);
     }
+
+    #[tokio::test]
+    async fn test_live_delivery_change() {
+        let request = MessagePickupResponseBuilder::new()
+            .live_delivery(true)
+            .build_live_delivery_change()
+            .unwrap()
+            .from("did:test");
+        assert_eq!(
+            request.get_didcomm_header().m_type,
+            "https://didcomm.org/messagepickup/2.0/live-delivery-change"
+        );
+
+        let handler = MessagePickupHandler::default();
+        let response = handler.handle(&request, None, None).await.unwrap();
+        assert_eq!(
+            response,
+            HandlerResponse::LiveDelivery("did:test".to_string(), true)
+        );
+    }
 }
@@ -4,4 +4,5 @@ pub mod discoverfeatures;
 pub mod forward;
 pub mod invitation;
 pub mod messagepickup;
+pub mod pushnotifications;
 pub mod trustping;
@@ -0,0 +1,218 @@
+// https://github.com/hyperledger/aries-rfcs/tree/main/features/0734-push-notifications-fcm
+// https://github.com/hyperledger/aries-rfcs/tree/main/features/0699-push-notifications-apns
+use crate::connections::{ConnectionStorage, DeviceInfo};
+use crate::handler::{DidcommHandler, HandlerResponse};
+use crate::keystore::KeyStore;
+use crate::registry::MessageType;
+use async_trait::async_trait;
+use didcomm_rs::Message;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::sync::Arc;
+
+pub struct PushNotificationsResponseBuilder {
+    platform: String,
+    device_token: Option<String>,
+    device: Option<DeviceInfo>,
+    message: Option<Message>,
+}
+
+impl Default for PushNotificationsResponseBuilder {
+    fn default() -> Self {
+        PushNotificationsResponseBuilder {
+            platform: "fcm".to_string(),
+            device_token: None,
+            device: None,
+            message: None,
+        }
+    }
+}
+
+impl PushNotificationsResponseBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn platform(&mut self, platform: String) -> &mut Self {
+        self.platform = platform;
+        self
+    }
+
+    pub fn device_token(&mut self, device_token: String) -> &mut Self {
+        self.device_token = Some(device_token);
+        self
+    }
+
+    pub fn device(&mut self, device: Option<DeviceInfo>) -> &mut Self {
+        self.device = device;
+        self
+    }
+
+    pub fn message(&mut self, message: Message) -> &mut Self {
+        self.message = Some(message);
+        self
+    }
+
+    fn m_type(&self, name: &str) -> String {
+        format!(
+            "https://didcomm.org/push-notifications-{}/1.0/{}",
+            self.platform, name
+        )
+    }
+
+    pub fn build(&mut self) -> Result<Message, &'static str> {
+        match &self.message {
+            Some(message) => match MessageType::parse(&message.get_didcomm_header().m_type) {
+                Some(m_type) if m_type.name == "get-device-info" => self.build_device_info(),
+                _ => Err("unsupported message"),
+            },
+            None => Err("no message"),
+        }
+    }
+
+    pub fn build_set_device_info(&mut self) -> Result<Message, &'static str> {
+        Ok(Message::new().m_type(&self.m_type("set-device-info")).body(
+            &json!({
+                "device_token": self.device_token,
+                "device_platform": self.platform,
+            })
+            .to_string(),
+        ))
+    }
+
+    pub fn build_get_device_info(&mut self) -> Result<Message, &'static str> {
+        Ok(Message::new()
+            .m_type(&self.m_type("get-device-info"))
+            .body(&json!({}).to_string()))
+    }
+
+    pub fn build_device_info(&mut self) -> Result<Message, &'static str> {
+        let thid = match &self.message {
+            Some(message) => message.get_didcomm_header().id.to_string(),
+            None => return Err("no message"),
+        };
+        let body = match &self.device {
+            Some(device) => json!({
+                "device_token": device.device_token,
+                "device_platform": device.device_platform,
+            }),
+            None => json!({
+                "device_token": null,
+                "device_platform": null,
+            }),
+        };
+        Ok(Message::new()
+            .m_type(&self.m_type("device-info"))
+            .thid(&thid)
+            .body(&body.to_string()))
+    }
+}
+
+#[derive(Default)]
+pub struct PushNotificationsHandler {}
+
+#[async_trait]
+impl DidcommHandler for PushNotificationsHandler {
+    async fn handle(
+        &self,
+        request: &Message,
+        _keys: Option<&dyn KeyStore>,
+        connections: Option<&Arc<dyn ConnectionStorage>>,
+    ) -> Result<HandlerResponse, Box<dyn Error>> {
+        let m_type = match MessageType::parse(&request.get_didcomm_header().m_type) {
+            Some(m_type) if m_type.protocol.starts_with("push-notifications-") => m_type,
+            _ => return Ok(HandlerResponse::Skipped),
+        };
+        let platform = m_type.protocol.trim_start_matches("push-notifications-");
+        let did = request
+            .get_didcomm_header()
+            .from
+            .clone()
+            .ok_or("no sender")?;
+        let connections = connections.ok_or("no connections")?;
+        match m_type.name.as_str() {
+            "set-device-info" => {
+                let body: Value = serde_json::from_str(&request.get_body()?)?;
+                let device = body["device_token"]
+                    .as_str()
+                    .map(|device_token| DeviceInfo {
+                        device_token: device_token.to_string(),
+                        device_platform: body["device_platform"]
+                            .as_str()
+                            .unwrap_or(platform)
+                            .to_string(),
+                    });
+                connections.set_device(did, device).await;
+                Ok(HandlerResponse::Processed)
+            }
+            "get-device-info" => {
+                let device = connections.get_device(did.to_string()).await;
+                let response = PushNotificationsResponseBuilder::new()
+                    .platform(platform.to_string())
+                    .device(device)
+                    .message(request.clone())
+                    .build()?;
+                Ok(HandlerResponse::Send(did, Box::new(response)))
+            }
+            _ => Ok(HandlerResponse::Skipped),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connections::Connections;
+
+    #[tokio::test]
+    async fn test_set_and_get_device_info() {
+        let connections: Arc<dyn ConnectionStorage> = Arc::new(Connections::new());
+        let handler = PushNotificationsHandler::default();
+
+        let set = PushNotificationsResponseBuilder::new()
+            .device_token("token".to_string())
+            .build_set_device_info()
+            .unwrap()
+            .from("did:key:test");
+        let response = handler.handle(&set, None, Some(&connections)).await;
+        assert_eq!(response.unwrap(), HandlerResponse::Processed);
+        assert_eq!(
+            connections.get_device("did:key:test".to_string()).await,
+            Some(DeviceInfo {
+                device_token: "token".to_string(),
+                device_platform: "fcm".to_string(),
+            })
+        );
+
+        let get = PushNotificationsResponseBuilder::new()
+            .build_get_device_info()
+            .unwrap()
+            .from("did:key:test");
+        let response = handler.handle(&get, None, Some(&connections)).await;
+        match response.unwrap() {
+            HandlerResponse::Send(did, response) => {
+                assert_eq!(did, "did:key:test");
+                assert_eq!(
+                    response.get_didcomm_header().m_type,
+                    "https://didcomm.org/push-notifications-fcm/1.0/device-info"
+                );
+                let body: Value = serde_json::from_str(&response.get_body().unwrap()).unwrap();
+                assert_eq!(body["device_token"], "token");
+            }
+            _ => panic!("expected device-info"),
+        }
+
+        let unset = PushNotificationsResponseBuilder::new()
+            .build_set_device_info()
+            .unwrap()
+            .from("did:key:test");
+        handler
+            .handle(&unset, None, Some(&connections))
+            .await
+            .unwrap();
+        assert!(connections
+            .get_device("did:key:test".to_string())
+            .await
+            .is_none());
+    }
+}
@@ -1,9 +1,9 @@
 // https://identity.foundation/didcomm-messaging/spec/#trust-ping-protocol-20
 use crate::connections::ConnectionStorage;
 use crate::handler::{DidcommHandler, HandlerResponse};
-use async_mutex::Mutex;
+use crate::keystore::KeyStore;
+use crate::message::{ReturnRoute, Transport};
 use async_trait::async_trait;
-use did_key::KeyPair;
 use didcomm_rs::Message;
 use serde_json::json;
 use std::error::Error;
@@ -13,6 +13,8 @@ use std::sync::Arc;
 pub struct TrustPingResponseBuilder {
     thid: Option<String>,
     message: Option<Message>,
+    return_route: Option<ReturnRoute>,
+    transport: Option<Transport>,
 }
 
 impl TrustPingResponseBuilder {
@@ -20,6 +22,8 @@ impl TrustPingResponseBuilder {
         TrustPingResponseBuilder {
             thid: None,
             message: None,
+            return_route: None,
+            transport: None,
         }
     }
 
@@ -33,6 +37,16 @@ impl TrustPingResponseBuilder {
         self
     }
 
+    pub fn return_route(&mut self, return_route: ReturnRoute) -> &mut Self {
+        self.return_route = Some(return_route);
+        self
+    }
+
+    pub fn transport(&mut self, transport: Transport) -> &mut Self {
+        self.transport = Some(transport);
+        self
+    }
+
     pub fn build(&mut self) -> Result<Message, &'static str> {
         match &self.message {
             Some(message) => match message.get_didcomm_header().m_type.as_str() {
@@ -44,9 +58,16 @@ impl TrustPingResponseBuilder {
     }
 
     pub fn build_ping(&mut self) -> Result<Message, &'static str> {
-        Ok(Message::new()
+        let mut message = Message::new()
             .m_type("https://didcomm.org/trust-ping/2.0/ping")
-            .body(&json!({"response_requested": true}).to_string()))
+            .body(&json!({"response_requested": true}).to_string());
+        if let Some(return_route) = &self.return_route {
+            message = return_route.apply(message);
+        }
+        if let Some(transport) = &self.transport {
+            message = transport.apply(message);
+        }
+        Ok(message)
     }
 
     pub fn build_response(&mut self) -> Result<Message, &'static str> {
@@ -68,8 +89,8 @@ impl DidcommHandler for TrustPingHandler {
     async fn handle(
         &self,
         request: &Message,
-        _key: Option<&KeyPair>,
-        _connections: Option<&Arc<Mutex<Box<dyn ConnectionStorage>>>>,
+        _keys: Option<&dyn KeyStore>,
+        _connections: Option<&Arc<dyn ConnectionStorage>>,
     ) -> Result<HandlerResponse, Box<dyn Error>> {
         if request
             .get_didcomm_header()
@@ -105,6 +126,28 @@ mod tests {
         println!("{}", serde_json::to_string_pretty(&response).unwrap());
     }
 
+    #[test]
+    fn test_build_ping_return_route() {
+        use crate::message::has_return_route_all_header;
+
+        let ping = TrustPingResponseBuilder::new()
+            .return_route(ReturnRoute::All)
+            .build()
+            .unwrap();
+        assert_eq!(ReturnRoute::from_message(&ping), Some(ReturnRoute::All));
+        assert!(has_return_route_all_header(&ping));
+
+        let ping = TrustPingResponseBuilder::new()
+            .transport(Transport::new(ReturnRoute::Thread))
+            .build()
+            .unwrap();
+        assert_eq!(ReturnRoute::from_message(&ping), None);
+        assert_eq!(
+            Transport::from_message(&ping),
+            Some(Transport::new(ReturnRoute::Thread))
+        );
+    }
+
     #[test]
     fn test_build_response() {
         let ping = TrustPingResponseBuilder::new().build().unwrap();
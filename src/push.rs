@@ -0,0 +1,73 @@
+use crate::connections::DeviceInfo;
+use async_trait::async_trait;
+
+#[async_trait]
+pub trait PushNotifier: Send + Sync {
+    async fn notify(&self, did: &str, device: &DeviceInfo);
+}
+
+#[cfg(feature = "webhooks")]
+pub struct WebhookPushNotifier {
+    client: reqwest::Client,
+    webhook: crate::config::WebhookConfig,
+}
+
+#[cfg(feature = "webhooks")]
+impl WebhookPushNotifier {
+    pub fn new(webhook: crate::config::WebhookConfig) -> Self {
+        WebhookPushNotifier {
+            client: reqwest::Client::new(),
+            webhook,
+        }
+    }
+}
+
+#[cfg(feature = "webhooks")]
+#[async_trait]
+impl PushNotifier for WebhookPushNotifier {
+    async fn notify(&self, did: &str, device: &DeviceInfo) {
+        let payload = serde_json::to_vec(&serde_json::json!({
+            "did": did,
+            "device_token": device.device_token,
+            "device_platform": device.device_platform,
+        }))
+        .unwrap();
+        tokio::spawn(crate::webhook::Webhooks::deliver(
+            self.client.clone(),
+            self.webhook.clone(),
+            payload,
+        ));
+    }
+}
+
+#[cfg(all(test, feature = "webhooks"))]
+mod tests {
+    use super::*;
+    use crate::config::WebhookConfig;
+    use crate::webhook::sign;
+    use crate::webhook::tests::stand_in;
+
+    #[tokio::test]
+    async fn test_webhook_push_notifier() {
+        let (url, mut received) = stand_in(vec![]).await;
+        let notifier = WebhookPushNotifier::new(WebhookConfig {
+            url,
+            secret: "secret".to_string(),
+            events: None,
+            retries: Some(0),
+            backoff_ms: None,
+        });
+        let device = DeviceInfo {
+            device_token: "token".to_string(),
+            device_platform: "apns".to_string(),
+        };
+        notifier.notify("did:key:test", &device).await;
+
+        let request = received.recv().await.unwrap();
+        assert_eq!(request.signature, Some(sign("secret", &request.body)));
+        let payload: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(payload["did"], "did:key:test");
+        assert_eq!(payload["device_token"], "token");
+        assert_eq!(payload["device_platform"], "apns");
+    }
+}
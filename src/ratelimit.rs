@@ -0,0 +1,228 @@
+use crate::config::{RateLimit, RateLimitConfig};
+use crate::handler::HandlerResponse;
+use crate::interceptor::Interceptor;
+use crate::registry::{HandlerRegistry, MessageType};
+use async_trait::async_trait;
+use chrono::Utc;
+use didcomm_rs::Message;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+
+const MAX_BUCKETS: usize = 10_000;
+
+#[derive(Debug)]
+pub struct RateLimitExceeded;
+
+impl fmt::Display for RateLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rate limit exceeded")
+    }
+}
+
+impl Error for RateLimitExceeded {}
+
+struct Bucket {
+    tokens: f64,
+    updated: i64,
+    used: u64,
+    limit: RateLimit,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: i64) {
+        let elapsed = (now - self.updated).max(0) as f64 / 1000.0;
+        self.tokens =
+            (self.tokens + elapsed * self.limit.per_second).min(self.limit.capacity as f64);
+        self.updated = now;
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    /// Counts checks, so the least recently used bucket can be evicted.
+    clock: u64,
+}
+
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn check(&self, key: &str, limit: &RateLimit) -> bool {
+        let now = Utc::now().timestamp_millis();
+        let mut guard = self.buckets.lock().unwrap();
+        let Buckets { buckets, clock } = &mut *guard;
+        *clock += 1;
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            buckets.retain(|_, bucket| {
+                bucket.refill(now);
+                bucket.tokens < bucket.limit.capacity as f64
+            });
+            // every bucket is draining: drop the least recently used one
+            if buckets.len() >= MAX_BUCKETS {
+                let oldest = buckets
+                    .iter()
+                    .min_by_key(|(_, bucket)| bucket.used)
+                    .map(|(key, _)| key.to_string());
+                if let Some(oldest) = oldest {
+                    buckets.remove(&oldest);
+                }
+            }
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert_with(|| Bucket {
+            tokens: limit.capacity as f64,
+            updated: now,
+            used: 0,
+            limit: limit.clone(),
+        });
+        bucket.used = *clock;
+        bucket.refill(now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+pub struct RateLimitInterceptor {
+    config: RateLimitConfig,
+    limiter: RateLimiter,
+}
+
+impl RateLimitInterceptor {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimitInterceptor {
+            config,
+            limiter: RateLimiter::new(),
+        }
+    }
+
+    fn limit(&self, did: &str, m_type: &str) -> Option<(String, &RateLimit)> {
+        let protocol = MessageType::parse(m_type).and_then(|m_type| {
+            self.config.protocols.as_ref()?.iter().find(|(entry, _)| {
+                HandlerRegistry::matches(entry, &m_type.protocol, &m_type.version)
+            })
+        });
+        match protocol {
+            Some((entry, limit)) => Some((format!("{} {}", did, entry), limit)),
+            None => self
+                .config
+                .per_did
+                .as_ref()
+                .map(|limit| (did.to_string(), limit)),
+        }
+    }
+}
+
+#[async_trait]
+impl Interceptor for RateLimitInterceptor {
+    async fn before_handle(
+        &self,
+        request: &mut Message,
+    ) -> Result<Option<HandlerResponse>, Box<dyn Error>> {
+        let header = request.get_didcomm_header();
+        // past `Mediator::receive`, `from` is the sender authenticated by the skid
+        let did = header
+            .from
+            .as_ref()
+            .ok_or("rate limiting requires an authenticated sender")?;
+        match self.limit(did, &header.m_type) {
+            Some((key, limit)) if !self.limiter.check(&key, limit) => {
+                Err(Box::new(RateLimitExceeded))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new();
+        let limit = RateLimit {
+            capacity: 2,
+            per_second: 0.0,
+        };
+        assert!(limiter.check("a", &limit));
+        assert!(limiter.check("a", &limit));
+        assert!(!limiter.check("a", &limit));
+        assert!(limiter.check("b", &limit));
+
+        let limit = RateLimit {
+            capacity: 1,
+            per_second: 1000.0,
+        };
+        assert!(limiter.check("c", &limit));
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert!(limiter.check("c", &limit));
+    }
+
+    #[tokio::test]
+    async fn test_interceptor_per_protocol() {
+        let mut protocols = HashMap::new();
+        protocols.insert(
+            "trust-ping".to_string(),
+            RateLimit {
+                capacity: 1,
+                per_second: 0.0,
+            },
+        );
+        let interceptor = RateLimitInterceptor::new(RateLimitConfig {
+            per_did: Some(RateLimit {
+                capacity: 2,
+                per_second: 0.0,
+            }),
+            protocols: Some(protocols),
+            ..Default::default()
+        });
+        let mut ping = Message::new()
+            .m_type("https://didcomm.org/trust-ping/2.0/ping")
+            .from("did:key:test");
+        let mut message = Message::new()
+            .m_type("https://didcomm.org/basicmessage/2.0/message")
+            .from("did:key:test");
+
+        assert!(interceptor.before_handle(&mut ping).await.is_ok());
+        let error = interceptor.before_handle(&mut ping).await.unwrap_err();
+        assert!(error.is::<RateLimitExceeded>());
+
+        assert!(interceptor.before_handle(&mut message).await.is_ok());
+        assert!(interceptor.before_handle(&mut message).await.is_ok());
+        assert!(interceptor.before_handle(&mut message).await.is_err());
+
+        let mut anonymous = Message::new().m_type("https://didcomm.org/basicmessage/2.0/message");
+        assert!(interceptor.before_handle(&mut anonymous).await.is_err());
+    }
+
+    #[test]
+    fn test_eviction() {
+        let limiter = RateLimiter::new();
+        let limit = RateLimit {
+            capacity: 1,
+            per_second: 0.0,
+        };
+        for key in 0..MAX_BUCKETS {
+            assert!(limiter.check(&key.to_string(), &limit));
+        }
+        assert!(!limiter.check("1", &limit));
+        assert!(limiter.check("new", &limit));
+        assert_eq!(limiter.buckets.lock().unwrap().buckets.len(), MAX_BUCKETS);
+        assert!(!limiter.check("1", &limit));
+        assert!(!limiter.check("new", &limit));
+        // "0" was the least recently used and got a fresh bucket
+        assert!(limiter.check("0", &limit));
+    }
+}
@@ -0,0 +1,227 @@
+use crate::config::{Config, MessageLimits};
+use crate::handler::DidcommHandler;
+use crate::protocols::basicmessage::BasicMessageHandler;
+use crate::protocols::didexchange::DidExchangeHandler;
+use crate::protocols::discoverfeatures::DiscoverFeaturesHandler;
+use crate::protocols::forward::ForwardHandler;
+use crate::protocols::messagepickup::MessagePickupHandler;
+use crate::protocols::pushnotifications::PushNotificationsHandler;
+use crate::protocols::trustping::TrustPingHandler;
+use std::collections::HashMap;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MessageType {
+    pub protocol: String,
+    pub version: String,
+    pub name: String,
+}
+
+impl MessageType {
+    pub fn parse(m_type: &str) -> Option<Self> {
+        let mut segments = m_type.trim_matches('"').rsplit('/');
+        let name = segments.next()?;
+        let version = segments.next()?;
+        let protocol = segments.next()?;
+        if name.is_empty() || version.is_empty() || protocol.is_empty() {
+            return None;
+        }
+        Some(MessageType {
+            protocol: protocol.to_string(),
+            version: version.to_string(),
+            name: name.to_string(),
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct HandlerRegistry {
+    handlers: HashMap<(String, String), Box<dyn DidcommHandler>>,
+}
+
+impl HandlerRegistry {
+    pub fn new() -> Self {
+        HandlerRegistry {
+            handlers: HashMap::new(),
+        }
+    }
+
+    pub fn with_defaults() -> Self {
+        Self::defaults(&MessageLimits::default())
+    }
+
+    fn defaults(limits: &MessageLimits) -> Self {
+        let mut registry = HandlerRegistry::new();
+        registry
+            .register("routing", "2.0", Box::new(ForwardHandler::default()))
+            .register(
+                "didexchange",
+                "1.0",
+                Box::new(DidExchangeHandler::default()),
+            )
+            .register(
+                "discover-features",
+                "2.0",
+                Box::new(DiscoverFeaturesHandler::default()),
+            )
+            .register("trust-ping", "2.0", Box::new(TrustPingHandler::default()))
+            .register(
+                "messagepickup",
+                "1.0",
+                Box::new(MessagePickupHandler::new(limits.max_batch_size)),
+            )
+            .register(
+                "messagepickup",
+                "2.0",
+                Box::new(MessagePickupHandler::new(limits.max_batch_size)),
+            )
+            .register(
+                "basicmessage",
+                "2.0",
+                Box::new(BasicMessageHandler::default()),
+            )
+            .register(
+                "push-notifications-fcm",
+                "1.0",
+                Box::new(PushNotificationsHandler::default()),
+            )
+            .register(
+                "push-notifications-apns",
+                "1.0",
+                Box::new(PushNotificationsHandler::default()),
+            );
+        registry
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        let mut registry = HandlerRegistry::defaults(&config.message_limits);
+        registry.handlers.retain(|(protocol, version), _| {
+            let enabled = match &config.protocols {
+                Some(protocols) => protocols
+                    .iter()
+                    .any(|entry| Self::matches(entry, protocol, version)),
+                None => true,
+            };
+            let disabled = match &config.disabled_protocols {
+                Some(protocols) => protocols
+                    .iter()
+                    .any(|entry| Self::matches(entry, protocol, version)),
+                None => false,
+            };
+            enabled && !disabled
+        });
+        registry
+    }
+
+    pub(crate) fn matches(entry: &str, protocol: &str, version: &str) -> bool {
+        entry == protocol || entry == format!("{}/{}", protocol, version)
+    }
+
+    pub fn register(
+        &mut self,
+        protocol: &str,
+        version: &str,
+        handler: Box<dyn DidcommHandler>,
+    ) -> &mut Self {
+        self.handlers
+            .insert((protocol.to_string(), version.to_string()), handler);
+        self
+    }
+
+    pub fn get(&self, m_type: &str) -> Option<&dyn DidcommHandler> {
+        let message_type = MessageType::parse(m_type)?;
+        self.handlers
+            .get(&(message_type.protocol, message_type.version))
+            .map(|handler| handler.as_ref())
+    }
+
+    /// `protocol/version` of the handler registered for `m_type`, so labels
+    /// never carry unregistered, sender-chosen values.
+    pub fn protocol(&self, m_type: &str) -> Option<String> {
+        let message_type = MessageType::parse(m_type)?;
+        let key = (message_type.protocol, message_type.version);
+        self.handlers
+            .contains_key(&key)
+            .then(|| format!("{}/{}", key.0, key.1))
+    }
+
+    pub fn protocols(&self) -> Vec<String> {
+        let mut protocols: Vec<String> = self
+            .handlers
+            .keys()
+            .map(|(protocol, version)| format!("{}/{}", protocol, version))
+            .collect();
+        protocols.sort();
+        protocols
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_message_type() {
+        let message_type =
+            MessageType::parse("https://didcomm.org/trust-ping/2.0/ping-response").unwrap();
+        assert_eq!(message_type.protocol, "trust-ping");
+        assert_eq!(message_type.version, "2.0");
+        assert_eq!(message_type.name, "ping-response");
+
+        let message_type =
+            MessageType::parse("\"https://didcomm.org/out-of-band/2.0/invitation\"").unwrap();
+        assert_eq!(message_type.protocol, "out-of-band");
+
+        assert!(MessageType::parse("ping").is_none());
+        assert!(MessageType::parse("https://didcomm.org/trust-ping/2.0/").is_none());
+    }
+
+    #[test]
+    fn test_get_handler() {
+        let registry = HandlerRegistry::with_defaults();
+        assert!(registry
+            .get("https://didcomm.org/trust-ping/2.0/ping")
+            .is_some());
+        assert!(registry
+            .get("https://didcomm.org/basicmessage/2.0/message")
+            .is_some());
+        assert!(registry
+            .get("https://didcomm.org/trust-ping/1.0/ping")
+            .is_none());
+        assert!(registry.get("unknown").is_none());
+    }
+
+    #[test]
+    fn test_protocol() {
+        let registry = HandlerRegistry::with_defaults();
+        assert_eq!(
+            registry.protocol("https://didcomm.org/trust-ping/2.0/ping"),
+            Some("trust-ping/2.0".to_string())
+        );
+        assert!(registry
+            .protocol("https://didcomm.org/made-up-protocol/9.9/anything")
+            .is_none());
+        assert!(registry.protocol("unknown").is_none());
+    }
+
+    #[test]
+    fn test_from_config() {
+        let config = Config {
+            disabled_protocols: Some(vec!["basicmessage".to_string()]),
+            ..Default::default()
+        };
+        let registry = HandlerRegistry::from_config(&config);
+        assert!(registry
+            .get("https://didcomm.org/basicmessage/2.0/message")
+            .is_none());
+        assert!(registry
+            .get("https://didcomm.org/trust-ping/2.0/ping")
+            .is_some());
+
+        let config = Config {
+            protocols: Some(vec!["trust-ping/2.0".to_string()]),
+            ..Default::default()
+        };
+        let registry = HandlerRegistry::from_config(&config);
+        assert_eq!(registry.protocols(), vec!["trust-ping/2.0".to_string()]);
+    }
+}
@@ -2,7 +2,9 @@ use did_key::KeyMaterial;
 use didcomm_rs::Error;
 
 pub async fn resolve(did: &str) -> Result<Vec<u8>, Error> {
-    Ok(did_key::resolve(did).unwrap().public_key_bytes())
+    did_key::resolve(did)
+        .map(|key| key.public_key_bytes())
+        .map_err(|_| Error::Generic(format!("could not resolve {}", did)))
 }
 
 #[cfg(test)]
@@ -2,6 +2,8 @@
 pub mod iota_resolver;
 pub mod key_resolver;
 
+use async_trait::async_trait;
+
 #[derive(Debug)]
 pub enum ResolveError {
     KeyResolveError(didcomm_rs::Error),
@@ -9,6 +11,21 @@ pub enum ResolveError {
     IotaResolveError(Box<dyn std::error::Error>),
 }
 
+#[async_trait]
+pub trait DidResolver: Send + Sync {
+    async fn resolve(&self, did: &str) -> Result<Vec<u8>, ResolveError>;
+}
+
+#[derive(Default)]
+pub struct DefaultResolver {}
+
+#[async_trait]
+impl DidResolver for DefaultResolver {
+    async fn resolve(&self, did: &str) -> Result<Vec<u8>, ResolveError> {
+        resolve(did).await
+    }
+}
+
 pub async fn resolve(did: &str) -> Result<Vec<u8>, ResolveError> {
     if did.starts_with("did:key:") {
         return match key_resolver::resolve(did).await {
@@ -45,4 +62,13 @@ mod tests {
         let resolved = resolve(&did).await.unwrap();
         assert_eq!(resolved, keypair.public_key_bytes());
     }
+
+    #[tokio::test]
+    async fn test_default_resolver() {
+        let keypair = generate::<X25519KeyPair>(None);
+        let did = keypair.get_did_document(Default::default()).id;
+
+        let resolved = DefaultResolver::default().resolve(&did).await.unwrap();
+        assert_eq!(resolved, keypair.public_key_bytes());
+    }
 }
@@ -0,0 +1,272 @@
+use crate::config::Config;
+use crate::didweb::url_to_did_web;
+use crate::keystore::KeyStore;
+use crate::mediator::Mediator;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// First path segments already taken by the routes of the default identity.
+const RESERVED: &[&str] = &[
+    "admin",
+    "didcomm",
+    "health",
+    "invitation",
+    "metrics",
+    "outofband",
+    "ready",
+    "ws",
+];
+
+pub fn check_tenant_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if !valid {
+        return Err(format!(
+            "tenant {} must be lowercase letters, digits, - and _",
+            name
+        ));
+    }
+    if RESERVED.contains(&name) {
+        return Err(format!("tenant {} collides with a mediator route", name));
+    }
+    Ok(())
+}
+
+impl Config {
+    /// Base URL of a tenant, its did:web resolves to `{url}/did.json`.
+    pub fn tenant_url(&self, name: &str) -> String {
+        format!("{}/{}", self.ext_hostname.trim_end_matches('/'), name)
+    }
+
+    pub fn tenant_service(&self, name: &str) -> String {
+        self.tenants
+            .get(name)
+            .and_then(|tenant| tenant.ext_service.clone())
+            .unwrap_or_else(|| format!("{}/didcomm", self.tenant_url(name)))
+    }
+
+    /// The wallet settings of a tenant, in place of those of the mediator.
+    pub fn tenant_wallet_config(&self, name: &str) -> Option<Config> {
+        let tenant = self.tenants.get(name)?;
+        Some(Config {
+            wallet_path: tenant.wallet_path.clone(),
+            keyfile_path: tenant.keyfile_path.clone(),
+            wallet_password: self.wallet_password.clone(),
+            key_seed: tenant.key_seed.clone(),
+            retired_keys: tenant.retired_keys.clone(),
+            key_grace_period: self.key_grace_period,
+            #[cfg(feature = "iota")]
+            did_iota: tenant.did_iota.clone(),
+            ..Default::default()
+        })
+    }
+
+    /// `connections.json` becomes `connections.{tenant}.json`.
+    pub fn tenant_storage_path(&self, name: &str) -> Option<String> {
+        if let Some(path) = self
+            .tenants
+            .get(name)
+            .and_then(|tenant| tenant.storage_path.clone())
+        {
+            return Some(path);
+        }
+        let path = Path::new(self.storage_path.as_ref()?);
+        let stem = path.file_stem()?.to_string_lossy();
+        let file_name = match path.extension() {
+            Some(extension) => format!("{}.{}.{}", stem, name, extension.to_string_lossy()),
+            None => format!("{}.{}", stem, name),
+        };
+        Some(path.with_file_name(file_name).to_string_lossy().to_string())
+    }
+}
+
+pub struct Tenant {
+    pub name: String,
+    pub ext_service: String,
+    pub did_web: String,
+    pub mediator: Mediator,
+    dids: Vec<String>,
+}
+
+impl Tenant {
+    pub fn new(name: &str, config: &Config, mediator: Mediator) -> Self {
+        let did_web = url_to_did_web(&config.tenant_url(name));
+        let mut dids: Vec<String> = mediator
+            .wallet()
+            .key_ids()
+            .iter()
+            .map(|kid| did_of_kid(kid).to_string())
+            .collect();
+        dids.push(did_web.to_string());
+        Tenant {
+            name: name.to_string(),
+            ext_service: config.tenant_service(name),
+            did_web,
+            mediator,
+            dids,
+        }
+    }
+
+    /// The DIDs messages to this tenant are encrypted for, including
+    /// retired keys. Tenant keys only rotate on restart, so they are
+    /// collected once; a retired key past its grace period fails to
+    /// decrypt rather than falling back to the default identity.
+    pub fn dids(&self) -> &[String] {
+        &self.dids
+    }
+}
+
+fn did_of_kid(kid: &str) -> &str {
+    kid.split('#').next().unwrap_or_default()
+}
+
+/// Key ids a JWE is encrypted for, from the general and the flattened
+/// JSON serialization.
+pub fn recipient_kids(raw: &str) -> Vec<String> {
+    let jwe: Value = match serde_json::from_str(raw) {
+        Ok(jwe) => jwe,
+        Err(_) => return vec![],
+    };
+    let mut kids: Vec<String> = jwe["recipients"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|recipient| recipient["header"]["kid"].as_str())
+        .map(str::to_string)
+        .collect();
+    if let Some(kid) = jwe["header"]["kid"].as_str() {
+        kids.push(kid.to_string());
+    }
+    kids
+}
+
+#[derive(Default)]
+pub struct Tenants {
+    tenants: HashMap<String, Tenant>,
+}
+
+impl Tenants {
+    pub fn insert(&mut self, tenant: Tenant) -> Result<(), String> {
+        check_tenant_name(&tenant.name)?;
+        let did = tenant.mediator.wallet().did_key();
+        if let Some(other) = self
+            .tenants
+            .values()
+            .find(|other| other.mediator.wallet().did_key() == did)
+        {
+            return Err(format!(
+                "tenants {} and {} share the key {}",
+                other.name, tenant.name, did
+            ));
+        }
+        self.tenants.insert(tenant.name.to_string(), tenant);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tenant> {
+        self.tenants.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Tenant> {
+        self.tenants.values()
+    }
+
+    /// The tenant a JWE is encrypted for, by the `kid` or DID of its
+    /// recipients. `None` leaves it to the default identity.
+    pub fn for_envelope(&self, raw: &str) -> Option<&Tenant> {
+        let kids = recipient_kids(raw);
+        self.tenants.values().find(|tenant| {
+            kids.iter()
+                .any(|kid| tenant.dids().iter().any(|did| did == did_of_kid(kid)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TenantConfig;
+    use crate::connections::Connections;
+    use crate::wallet::Wallet;
+    use std::sync::Arc;
+
+    fn tenant(name: &str, config: &Config) -> Tenant {
+        let mediator = Mediator::new(Wallet::default(), Arc::new(Connections::new()));
+        Tenant::new(name, config, mediator)
+    }
+
+    #[test]
+    fn test_tenant_config() {
+        let mut config = Config {
+            ext_hostname: "https://mediator.example/".to_string(),
+            storage_path: Some("/var/lib/mediator/connections.json".to_string()),
+            ..Default::default()
+        };
+        config.tenants.insert(
+            "beta".to_string(),
+            TenantConfig {
+                ext_service: Some("https://beta.example/didcomm".to_string()),
+                storage_path: Some("beta.json".to_string()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            config.tenant_service("acme"),
+            "https://mediator.example/acme/didcomm"
+        );
+        assert_eq!(
+            config.tenant_storage_path("acme").unwrap(),
+            "/var/lib/mediator/connections.acme.json"
+        );
+        assert_eq!(
+            config.tenant_service("beta"),
+            "https://beta.example/didcomm"
+        );
+        assert_eq!(config.tenant_storage_path("beta").unwrap(), "beta.json");
+        let wallet_config = config.tenant_wallet_config("beta").unwrap();
+        assert_eq!(wallet_config.key_seed, None);
+        assert_eq!(wallet_config.wallet_path, None);
+        assert!(config.tenant_wallet_config("acme").is_none());
+        assert_eq!(
+            tenant("acme", &config).did_web,
+            "did:web:mediator.example:acme"
+        );
+
+        assert!(check_tenant_name("acme-2").is_ok());
+        assert!(check_tenant_name("admin").is_err());
+        assert!(check_tenant_name("../acme").is_err());
+    }
+
+    #[test]
+    fn test_for_envelope() {
+        let config = Config {
+            ext_hostname: "https://mediator.example".to_string(),
+            ..Default::default()
+        };
+        let mut tenants = Tenants::default();
+        tenants.insert(tenant("acme", &config)).unwrap();
+        tenants.insert(tenant("beta", &config)).unwrap();
+        let acme_kid = tenants.get("acme").unwrap().mediator.wallet().key_ids()[0].clone();
+
+        let jwe = serde_json::json!({
+            "recipients": [{"header": {"kid": "did:key:other#other"}}, {"header": {"kid": acme_kid}}]
+        });
+        let routed = tenants.for_envelope(&jwe.to_string()).unwrap();
+        assert_eq!(routed.name, "acme");
+
+        let jwe = serde_json::json!({"header": {"kid": "did:web:mediator.example:beta#key-1"}});
+        assert_eq!(tenants.for_envelope(&jwe.to_string()).unwrap().name, "beta");
+        assert!(tenants.for_envelope("{}").is_none());
+        assert!(tenants.for_envelope("not json").is_none());
+
+        let seed = tenants.get("acme").unwrap().mediator.wallet().seed();
+        let copy = Mediator::new(
+            Wallet::new(Some(seed.to_string())),
+            Arc::new(Connections::new()),
+        );
+        assert!(tenants.insert(Tenant::new("copy", &config, copy)).is_err());
+    }
+}
@@ -1,13 +1,73 @@
 use crate::config::Config;
+use crate::fromprior::FromPrior;
+#[cfg(feature = "keyfile")]
+use crate::keyfile::Keyfile;
 use base58::{FromBase58, ToBase58};
-use did_key::{generate, DIDCore, KeyMaterial, KeyPair, X25519KeyPair};
+use chrono::Utc;
+use did_key::{generate, DIDCore, Ed25519KeyPair, KeyMaterial, KeyPair, X25519KeyPair};
 #[cfg(feature = "iota")]
 use identity_iota::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+#[cfg(feature = "keyfile")]
+use std::sync::Mutex;
+use std::sync::RwLock;
+use tracing::info;
+#[cfg(feature = "keyfile")]
+use tracing::warn;
+use zeroize::{Zeroize, Zeroizing};
 
-pub struct Wallet {
+/// One week.
+pub const DEFAULT_GRACE_PERIOD: i64 = 7 * 24 * 60 * 60;
+
+/// A previous key, still accepted for decryption until its grace period ends.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetiredKey {
     pub seed: String,
+    /// The Ed25519 key signing alongside `seed`, derived from `seed` when
+    /// missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing: Option<String>,
+    pub retired_at: i64,
+}
+
+impl RetiredKey {
+    fn signing_keypair(&self) -> KeyPair {
+        let seed = match &self.signing {
+            Some(signing) => Zeroizing::new(signing.to_string()),
+            None => Wallet::derive_signing_seed(&self.seed),
+        };
+        generate::<Ed25519KeyPair>(Some(&seed.from_base58().unwrap()))
+    }
+}
+
+impl Drop for RetiredKey {
+    fn drop(&mut self) {
+        self.seed.zeroize();
+        if let Some(signing) = self.signing.as_mut() {
+            signing.zeroize();
+        }
+    }
+}
+
+#[cfg(feature = "keyfile")]
+struct OpenKeyfile {
+    path: String,
+    password: Zeroizing<String>,
+    keyfile: Mutex<Keyfile>,
+}
+
+pub struct Wallet {
+    seed: RwLock<Zeroizing<String>>,
+    /// Ed25519 key, rotated and retired along with `seed`.
+    signing_seed: RwLock<Zeroizing<String>>,
+    retired: RwLock<Vec<RetiredKey>>,
+    grace_period: i64,
     #[cfg(feature = "iota")]
     pub account: Option<identity_iota::account::Account>,
+    #[cfg(feature = "keyfile")]
+    keyfile: Option<OpenKeyfile>,
 }
 
 impl Default for Wallet {
@@ -22,33 +82,254 @@ impl Wallet {
     pub fn new(seed: Option<String>) -> Self {
         match seed {
             Some(seed) => Wallet {
-                seed,
+                signing_seed: RwLock::new(Self::derive_signing_seed(&seed)),
+                seed: RwLock::new(Zeroizing::new(seed)),
+                retired: RwLock::new(Vec::new()),
+                grace_period: DEFAULT_GRACE_PERIOD,
                 #[cfg(feature = "iota")]
                 account: None,
+                #[cfg(feature = "keyfile")]
+                keyfile: None,
             },
             _ => Wallet::default(),
         }
     }
 
-    pub async fn new_from_config(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
-        match config.key_seed.clone() {
-            Some(seed) => Ok(Wallet {
-                seed,
-                #[cfg(feature = "iota")]
+    pub async fn new_from_config(config: &Config) -> Result<Self, Box<dyn Error>> {
+        let wallet = Self::open_from_config(config).await?;
+        for key in config.retired_keys.iter().flatten() {
+            let mut retired = wallet.retired.write().unwrap();
+            if retired.iter().all(|retired| retired.seed != key.seed) {
+                retired.push(key.clone());
+            }
+        }
+        wallet
+            .retired
+            .write()
+            .unwrap()
+            .sort_by_key(|retired| retired.retired_at);
+        Ok(wallet.grace_period(config.key_grace_period.unwrap_or(DEFAULT_GRACE_PERIOD)))
+    }
+
+    async fn open_from_config(config: &Config) -> Result<Self, Box<dyn Error>> {
+        #[cfg(all(feature = "keyfile", not(feature = "iota")))]
+        if let (Some(path), Some(password)) = (&config.keyfile_path, &config.wallet_password) {
+            return Self::open_keyfile(path, password, config.key_seed.as_deref());
+        }
+        Ok(match config.key_seed.clone() {
+            #[cfg(feature = "iota")]
+            Some(seed) if config.wallet_path.is_some() => Wallet {
                 account: Some(Self::load_iota_account(config).await?),
+                ..Wallet::new(Some(seed))
+            },
+            Some(seed) => Wallet::new(Some(seed)),
+            _ => Wallet::default(),
+        })
+    }
+
+    /// Ed25519 seed of a wallet without keyfile, derived from its X25519
+    /// seed so the signing key is stable across restarts.
+    fn derive_signing_seed(seed: &str) -> Zeroizing<String> {
+        let digest = Sha256::new()
+            .chain_update(b"didcomm-mediator ed25519")
+            .chain_update(seed.as_bytes())
+            .finalize();
+        Zeroizing::new(digest.to_base58())
+    }
+
+    /// Loads the keyfile at `path`, creating it from `seed` or fresh keys
+    /// when it does not exist yet. An existing keyfile wins over `seed`.
+    #[cfg(feature = "keyfile")]
+    pub fn open_keyfile(
+        path: &str,
+        password: &str,
+        seed: Option<&str>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let keyfile = if std::path::Path::new(path).exists() {
+            let keyfile = Keyfile::load(path, password)?;
+            if matches!(seed, Some(seed) if seed != keyfile.x25519) {
+                warn!(path, "key_seed differs from the keyfile, using the keyfile");
+            }
+            keyfile
+        } else {
+            let keyfile = match seed {
+                Some(seed) => Keyfile::from_seed(seed),
+                None => Keyfile::generate(),
+            };
+            keyfile.save(path, password)?;
+            info!(path, "created keyfile");
+            keyfile
+        };
+        Ok(Wallet {
+            seed: RwLock::new(Zeroizing::new(keyfile.x25519.to_string())),
+            signing_seed: RwLock::new(Zeroizing::new(keyfile.ed25519.to_string())),
+            retired: RwLock::new(keyfile.retired.clone()),
+            grace_period: DEFAULT_GRACE_PERIOD,
+            #[cfg(feature = "iota")]
+            account: None,
+            keyfile: Some(OpenKeyfile {
+                path: path.to_string(),
+                password: Zeroizing::new(password.to_string()),
+                keyfile: Mutex::new(keyfile),
             }),
-            _ => Ok(Wallet::default()),
-        }
+        })
+    }
+
+    /// Seconds a retired key keeps decrypting messages after a rotation.
+    pub fn grace_period(mut self, grace_period: i64) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    #[cfg(feature = "keyfile")]
+    pub fn keyfile(&self) -> Option<Keyfile> {
+        self.keyfile
+            .as_ref()
+            .map(|open| open.keyfile.lock().unwrap().clone())
+    }
+
+    pub fn seed(&self) -> Zeroizing<String> {
+        self.seed.read().unwrap().clone()
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        matches!(self.seed.read().unwrap().from_base58(), Ok(private) if private.len() == 32)
     }
 
     pub fn keypair(&self) -> KeyPair {
-        generate::<X25519KeyPair>(Some(&self.seed.from_base58().unwrap()))
+        generate::<X25519KeyPair>(Some(&self.seed.read().unwrap().from_base58().unwrap()))
     }
 
     pub fn did_key(&self) -> String {
         self.keypair().get_did_document(Default::default()).id
     }
 
+    /// The Ed25519 key the wallet signs with.
+    pub fn signing_keypair(&self) -> KeyPair {
+        generate::<Ed25519KeyPair>(Some(
+            &self.signing_seed.read().unwrap().from_base58().unwrap(),
+        ))
+    }
+
+    /// did:key of [`Wallet::signing_keypair`], published as `authentication`
+    /// in the DID document.
+    pub fn signing_did(&self) -> String {
+        self.signing_keypair()
+            .get_did_document(Default::default())
+            .id
+    }
+
+    /// Whether [`Wallet::rotate`] saves the new key, rather than losing it
+    /// on restart.
+    pub fn persists_rotation(&self) -> bool {
+        #[cfg(feature = "keyfile")]
+        return self.keyfile.is_some();
+        #[cfg(not(feature = "keyfile"))]
+        false
+    }
+
+    /// Replaces the key with a fresh one and retires the old key for the
+    /// grace period. Returns the new did:key.
+    pub fn rotate(&self) -> Result<String, Box<dyn Error>> {
+        let seed = generate::<X25519KeyPair>(None)
+            .private_key_bytes()
+            .to_base58();
+        let signing = generate::<Ed25519KeyPair>(None)
+            .private_key_bytes()
+            .to_base58();
+        // held until the keys are swapped, so rotations do not interleave
+        #[cfg(feature = "keyfile")]
+        let mut keyfile = self
+            .keyfile
+            .as_ref()
+            .map(|open| (open, open.keyfile.lock().unwrap()));
+        self.prune_retired();
+        let mut retired = self.retired.read().unwrap().clone();
+        retired.push(RetiredKey {
+            seed: self.seed().to_string(),
+            signing: Some(self.signing_seed.read().unwrap().to_string()),
+            retired_at: Utc::now().timestamp(),
+        });
+        // saved first, so a failed save leaves the wallet on the old key
+        #[cfg(feature = "keyfile")]
+        if let Some((open, keyfile)) = keyfile.as_mut() {
+            let rotated = Keyfile {
+                x25519: seed.to_string(),
+                ed25519: signing.to_string(),
+                retired: retired.clone(),
+            };
+            rotated.save(&open.path, &open.password)?;
+            **keyfile = rotated;
+        }
+        *self.seed.write().unwrap() = Zeroizing::new(seed);
+        *self.signing_seed.write().unwrap() = Zeroizing::new(signing);
+        *self.retired.write().unwrap() = retired;
+        let did = self.did_key();
+        info!(%did, grace_period = self.grace_period, "rotated key");
+        Ok(did)
+    }
+
+    fn prune_retired(&self) {
+        let expired = Utc::now().timestamp() - self.grace_period;
+        self.retired
+            .write()
+            .unwrap()
+            .retain(|retired| retired.retired_at > expired);
+    }
+
+    /// `from_prior` JWT to the current key, while the latest retired key is
+    /// within its grace period. X25519 keys cannot sign, so it is issued by
+    /// the retired Ed25519 key the DID document published under
+    /// `authentication` until the rotation.
+    pub async fn from_prior(&self) -> Option<String> {
+        let prior_did = self
+            .retired_signing_keypairs()
+            .first()?
+            .get_did_document(Default::default())
+            .id;
+        FromPrior::new(&prior_did, &self.did_key())
+            .sign(self)
+            .await
+            .ok()
+    }
+
+    /// The current signing key followed by the retired ones.
+    pub(crate) fn signing_keypairs(&self) -> Vec<KeyPair> {
+        let mut keypairs = vec![self.signing_keypair()];
+        keypairs.extend(self.retired_signing_keypairs());
+        keypairs
+    }
+
+    fn retired_signing_keypairs(&self) -> Vec<KeyPair> {
+        self.prune_retired();
+        self.retired
+            .read()
+            .unwrap()
+            .iter()
+            .rev()
+            .map(RetiredKey::signing_keypair)
+            .collect()
+    }
+
+    /// The current key followed by the retired keys, see [`crate::keystore::KeyStore`].
+    pub(crate) fn keypairs(&self) -> Vec<KeyPair> {
+        let mut keypairs = vec![self.keypair()];
+        keypairs.extend(self.retired_keypairs());
+        keypairs
+    }
+
+    /// Retired keys still within their grace period, newest first.
+    pub fn retired_keypairs(&self) -> Vec<KeyPair> {
+        self.prune_retired();
+        self.retired
+            .read()
+            .unwrap()
+            .iter()
+            .rev()
+            .map(|retired| generate::<X25519KeyPair>(Some(&retired.seed.from_base58().unwrap())))
+            .collect()
+    }
+
     #[cfg(feature = "iota")]
     pub fn did_iota(&self) -> Option<String> {
         self.account
@@ -119,7 +400,7 @@ impl Wallet {
                     .autopublish(true)
                     .create_identity(id_setup)
                     .await?;
-                println!("created new identity: {:?}", account.did());
+                info!(did = %account.did(), "created new identity");
                 let keypair = identity_iota::prelude::KeyPair::try_from_private_key_bytes(
                     KeyType::X25519,
                     &private,
@@ -148,18 +429,16 @@ impl Wallet {
     }
 
     pub fn log(&self) {
-        println!("did key: {}", self.did_key());
+        info!(did = %self.did_key(), "did key");
         #[cfg(feature = "iota")]
         {
             use identity_iota::client::ExplorerUrl;
             let explorer: &ExplorerUrl = ExplorerUrl::mainnet();
-            if self.account.is_some() {
-                println!("did iota: {}", self.account.as_ref().unwrap().did());
-                println!(
-                    "Explore the DID Document = {}",
-                    explorer
-                        .resolver_url(self.account.as_ref().unwrap().did())
-                        .unwrap()
+            if let Some(account) = self.account.as_ref() {
+                info!(
+                    did = %account.did(),
+                    explorer = %explorer.resolver_url(account.did()).unwrap(),
+                    "did iota"
                 );
             }
         }
@@ -169,18 +448,34 @@ impl Wallet {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::keystore::KeyStore;
 
     #[test]
     fn test_default() {
         let wallet = Wallet::default();
-        assert_ne!(wallet.seed, "");
+        assert_ne!(wallet.seed().as_str(), "");
     }
 
     #[test]
     fn test_new() {
         let wallet1 = Wallet::default();
-        let wallet2 = Wallet::new(Some(wallet1.seed.to_string()));
-        assert_eq!(wallet1.seed, wallet2.seed);
+        let wallet2 = Wallet::new(Some(wallet1.seed().to_string()));
+        assert_eq!(wallet1.seed(), wallet2.seed());
+        assert_eq!(
+            wallet1.signing_keypair().public_key_bytes(),
+            wallet2.signing_keypair().public_key_bytes()
+        );
+        wallet2.rotate().unwrap();
+        assert_ne!(
+            wallet1.signing_keypair().public_key_bytes(),
+            wallet2.signing_keypair().public_key_bytes()
+        );
+    }
+
+    #[test]
+    fn test_is_loaded() {
+        assert!(Wallet::default().is_loaded());
+        assert!(!Wallet::new(Some("0OIl".to_string())).is_loaded());
     }
 
     #[test]
@@ -189,6 +484,133 @@ mod tests {
         assert_ne!(wallet.keypair().private_key_bytes(), Vec::<u8>::new());
     }
 
+    #[cfg(feature = "keyfile")]
+    #[test]
+    fn test_open_keyfile() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wallet.keys");
+        let path = path.to_str().unwrap();
+        let seed = Wallet::default().seed().to_string();
+
+        let created = Wallet::open_keyfile(path, "password", Some(&seed)).unwrap();
+        assert_eq!(created.seed().as_str(), seed);
+        let loaded = Wallet::open_keyfile(path, "password", None).unwrap();
+        assert_eq!(loaded.did_key(), created.did_key());
+        assert_eq!(
+            loaded.keyfile().unwrap().ed25519,
+            created.keyfile().unwrap().ed25519
+        );
+        assert_eq!(
+            loaded.signing_keypair().private_key_bytes().to_base58(),
+            loaded.keyfile().unwrap().ed25519.as_str()
+        );
+        assert!(Wallet::open_keyfile(path, "wrong", None).is_err());
+
+        let did = loaded.rotate().unwrap();
+        let reopened = Wallet::open_keyfile(path, "password", None).unwrap();
+        assert_eq!(reopened.did_key(), did);
+        assert_eq!(
+            reopened.retired_keypairs()[0].public_key_bytes(),
+            created.keypair().public_key_bytes()
+        );
+        assert_eq!(
+            reopened.signing_keypairs()[1].public_key_bytes(),
+            created.signing_keypair().public_key_bytes()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "keyfile")]
+    #[test]
+    fn test_rotate_unsaved() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wallet.keys");
+        let wallet = Wallet::open_keyfile(path.to_str().unwrap(), "password", None).unwrap();
+        let did = wallet.did_key();
+        let signing_did = wallet.signing_did();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(wallet.rotate().is_err());
+        assert_eq!(wallet.did_key(), did);
+        assert_eq!(wallet.signing_did(), signing_did);
+        assert!(wallet.retired_keypairs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_expired_retired_key() {
+        let expired = Wallet::default();
+        let fresh = Wallet::default();
+        let grace_period = 60;
+        let config = Config {
+            wallet_path: None,
+            keyfile_path: None,
+            key_grace_period: Some(grace_period),
+            retired_keys: Some(vec![
+                RetiredKey {
+                    seed: expired.seed().to_string(),
+                    signing: None,
+                    retired_at: Utc::now().timestamp() - grace_period - 1,
+                },
+                RetiredKey {
+                    seed: fresh.seed().to_string(),
+                    signing: None,
+                    retired_at: Utc::now().timestamp(),
+                },
+            ]),
+            ..Default::default()
+        };
+        let wallet = Wallet::new_from_config(&config).await.unwrap();
+        let kids = wallet.key_ids();
+        assert_eq!(kids.len(), 2);
+
+        let sender = generate::<X25519KeyPair>(None);
+        let message = didcomm_rs::Message::new()
+            .from(&sender.did())
+            .body(r#"{"foo":"bar"}"#);
+        for (retired, opens) in [(&expired, false), (&fresh, true)] {
+            let kid = retired.key_ids().remove(0);
+            let sealed = sender
+                .seal(
+                    &sender.key_ids()[0],
+                    &message.clone().to(&[&retired.did()]),
+                    retired.keypair().public_key_bytes(),
+                )
+                .await
+                .unwrap();
+            let opened = wallet.open(&kid, &sealed, sender.public_key_bytes()).await;
+            assert_eq!(opened.is_ok(), opens);
+        }
+    }
+
+    #[test]
+    fn test_rotate() {
+        let wallet = Wallet::default();
+        let old = wallet.keypair();
+        let did = wallet.rotate().unwrap();
+        assert_eq!(did, wallet.did_key());
+        assert_ne!(did, old.get_did_document(Default::default()).id);
+        let retired = wallet.retired_keypairs();
+        assert_eq!(retired.len(), 1);
+        assert_eq!(retired[0].private_key_bytes(), old.private_key_bytes());
+
+        let wallet = Wallet::default().grace_period(0);
+        wallet.rotate().unwrap();
+        assert!(wallet.retired_keypairs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_from_prior() {
+        let wallet = Wallet::default();
+        assert!(wallet.from_prior().await.is_none());
+        let prior = wallet.signing_did();
+        wallet.rotate().unwrap();
+        let from_prior = FromPrior::verify(&wallet.from_prior().await.unwrap()).unwrap();
+        assert_eq!(from_prior.iss, prior);
+        assert_eq!(from_prior.sub, wallet.did_key());
+    }
+
     #[tokio::test]
     async fn test_new_from_config() {
         let mut config = Config::default();
@@ -201,10 +623,34 @@ mod tests {
         {
             assert_eq!(wallet1.did_iota().unwrap(), config.did_iota.unwrap());
             assert_eq!(wallet2.did_iota(), None);
-            assert_ne!(wallet1.seed, "");
+            assert_ne!(wallet1.seed().as_str(), "");
             let mut config = Config::default();
             config.did_iota = None;
             assert!(Wallet::new_from_config(&config).await.is_err());
         }
     }
+
+    #[cfg(all(feature = "keyfile", not(feature = "iota")))]
+    #[tokio::test]
+    async fn test_new_from_config_keyfile() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let retired = Wallet::default();
+        let config = Config {
+            keyfile_path: Some(dir.join("wallet.keys").to_str().unwrap().to_string()),
+            retired_keys: Some(vec![RetiredKey {
+                seed: retired.seed().to_string(),
+                signing: None,
+                retired_at: Utc::now().timestamp(),
+            }]),
+            ..Default::default()
+        };
+        let wallet = Wallet::new_from_config(&config).await.unwrap();
+        assert_eq!(wallet.seed().as_str(), crate::config::DEMO_KEY_SEED);
+        assert_eq!(
+            wallet.retired_keypairs()[0].public_key_bytes(),
+            retired.keypair().public_key_bytes()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,213 @@
+use crate::config::WebhookConfig;
+use crate::events::{EventListener, MediatorEvent};
+use async_trait::async_trait;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+const RETRIES: u32 = 3;
+const BACKOFF_MS: u64 = 500;
+
+pub fn sign(secret: &str, payload: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(payload);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+pub struct Webhooks {
+    client: reqwest::Client,
+    webhooks: Vec<WebhookConfig>,
+}
+
+impl Webhooks {
+    pub fn new(webhooks: Vec<WebhookConfig>) -> Self {
+        Webhooks {
+            client: reqwest::Client::new(),
+            webhooks,
+        }
+    }
+
+    fn payload(event: &MediatorEvent) -> Vec<u8> {
+        let mut payload = serde_json::to_value(event).unwrap();
+        payload["timestamp"] = Utc::now().timestamp().into();
+        serde_json::to_vec(&payload).unwrap()
+    }
+
+    pub(crate) async fn deliver(
+        client: reqwest::Client,
+        webhook: WebhookConfig,
+        payload: Vec<u8>,
+    ) -> bool {
+        let signature = sign(&webhook.secret, &payload);
+        let retries = webhook.retries.unwrap_or(RETRIES);
+        let backoff = webhook.backoff_ms.unwrap_or(BACKOFF_MS);
+        for attempt in 0..=retries {
+            let response = client
+                .post(&webhook.url)
+                .header("Content-Type", "application/json")
+                .header(SIGNATURE_HEADER, &signature)
+                .body(payload.clone())
+                .send()
+                .await;
+            match response {
+                Ok(response) if response.status().is_success() => {
+                    debug!(url = %webhook.url, attempt, "delivered webhook");
+                    return true;
+                }
+                Ok(response) => {
+                    warn!(url = %webhook.url, attempt, status = %response.status(), "webhook rejected")
+                }
+                Err(error) => warn!(url = %webhook.url, attempt, %error, "webhook failed"),
+            }
+            if attempt < retries {
+                tokio::time::sleep(Duration::from_millis(backoff << attempt)).await;
+            }
+        }
+        false
+    }
+}
+
+#[async_trait]
+impl EventListener for Webhooks {
+    async fn notify(&self, event: &MediatorEvent) {
+        let payload = Self::payload(event);
+        for webhook in &self.webhooks {
+            let subscribed = match &webhook.events {
+                Some(events) => events.iter().any(|name| name == event.name()),
+                None => true,
+            };
+            if subscribed {
+                tokio::spawn(Self::deliver(
+                    self.client.clone(),
+                    webhook.clone(),
+                    payload.clone(),
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    pub struct Received {
+        pub signature: Option<String>,
+        pub body: Vec<u8>,
+    }
+
+    /// Minimal HTTP server answering each request with the next status in `statuses`.
+    pub async fn stand_in(statuses: Vec<u16>) -> (String, UnboundedReceiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, receiver) = unbounded_channel();
+        tokio::spawn(async move {
+            let mut statuses = statuses.into_iter();
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 1024];
+                let header_end = loop {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                    if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break end + 4;
+                    }
+                };
+                let head = String::from_utf8_lossy(&request[..header_end]).to_lowercase();
+                let header = |name: &str| {
+                    head.lines()
+                        .find_map(|line| line.strip_prefix(name))
+                        .map(|value| value.trim().to_string())
+                };
+                let length: usize = header("content-length:")
+                    .and_then(|length| length.parse().ok())
+                    .unwrap_or(0);
+                while request.len() < header_end + length {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                let status = statuses.next().unwrap_or(200);
+                stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 {} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                            status
+                        )
+                        .as_bytes(),
+                    )
+                    .await
+                    .unwrap();
+                let _ = sender.send(Received {
+                    signature: header("x-webhook-signature:"),
+                    body: request[header_end..header_end + length].to_vec(),
+                });
+            }
+        });
+        (url, receiver)
+    }
+
+    fn webhook(url: String, events: Option<Vec<String>>) -> WebhookConfig {
+        WebhookConfig {
+            url,
+            secret: "secret".to_string(),
+            events,
+            retries: Some(2),
+            backoff_ms: Some(10),
+        }
+    }
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            sign("key", b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_signed_and_retried() {
+        let (url, mut received) = stand_in(vec![500, 503, 200]).await;
+        let webhooks = Webhooks::new(vec![webhook(url, None)]);
+        let event = MediatorEvent::MediationGranted {
+            did: "did:key:test".to_string(),
+        };
+        webhooks.notify(&event).await;
+
+        for _ in 0..3 {
+            let request = received.recv().await.unwrap();
+            assert_eq!(request.signature, Some(sign("secret", &request.body)));
+            let payload: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            assert_eq!(payload["event"], "mediation_granted");
+            assert_eq!(payload["did"], "did:key:test");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_event_filter() {
+        let (url, mut received) = stand_in(vec![]).await;
+        let webhooks = Webhooks::new(vec![webhook(url, Some(vec!["message_queued".to_string()]))]);
+        webhooks
+            .notify(&MediatorEvent::ConnectionCompleted {
+                did: "did:key:test".to_string(),
+            })
+            .await;
+        webhooks
+            .notify(&MediatorEvent::MessageQueued {
+                did: "did:key:test".to_string(),
+                message_id: "42".to_string(),
+            })
+            .await;
+
+        let request = received.recv().await.unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(payload["event"], "message_queued");
+    }
+}